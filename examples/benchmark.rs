@@ -1,39 +1,48 @@
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use ibv::connection::conn::connect;
 use ibv::connection::server::Server;
-use tokio::sync::oneshot;
+use tokio::sync::Notify;
 use tokio::task;
 
 #[tokio::main]
 async fn main() {
-    let total = 100_000;
-    let num_iterations = 10; // Number of iterations to repeat the benchmark
+    let total: u32 = 100_000;
+    let num_iterations: u32 = 10; // Number of iterations to repeat the benchmark
     let mut total_elapsed = Duration::default();
     let mut total_throughput = 0.0;
     let mut total_latency = Duration::default();
     let mut total_qps = 0.0;
 
-    let (tx, rx) = oneshot::channel::<()>();
+    // shared by the tasks of all connections, each counts into it
+    let received = Arc::new(AtomicU32::new(0));
+    let done = Arc::new(Notify::new());
 
-    let _server_task = task::spawn(async move {
-        let mut server = Server::new("127.0.0.1:7777".to_owned()).await;
+    let server_task = task::spawn(async move {
+        let mut server = Server::new("127.0.0.1:7777".to_owned()).await.unwrap();
         println!("Server ready to use");
-        let mut count: u32 = 0;
         loop {
-            let conn = Arc::new(server.accept().await);
+            let conn = tokio::select! {
+                conn = server.accept() => Arc::new(conn.unwrap()),
+                _ = done.notified() => break,
+            };
+            let received = received.clone();
+            let done = done.clone();
             tokio::spawn(async move {
-                loop {
-                    let conn = conn.clone(); // Clone conn before moving into the task
-
-                    let msg = conn.recv_msg().await.unwrap();
+                let mut handles = vec![];
+                // until the client closes the connection
+                while let Ok(msg) = conn.recv_msg().await {
                     // Handle data and response
-                    count += 1;
-                    let data = msg.to_vec();
-                    conn.release(msg).await;
+                    let count = received.fetch_add(1, Ordering::AcqRel) + 1;
+                    drop(msg);
+                    if count == total * num_iterations {
+                        done.notify_one();
+                    }
 
-                    tokio::spawn(async move {
+                    let conn = conn.clone(); // Clone conn before moving into the task
+                    handles.push(tokio::spawn(async move {
                         let response = count.to_be_bytes();
                         let response = &[std::io::IoSlice::new(&response)];
 
@@ -41,16 +50,19 @@ async fn main() {
                             Ok(_) => (),
                             Err(err) => println!("Error: {}", err),
                         }
-                    });
+                    }));
+                }
+                for handle in handles {
+                    handle.await.unwrap();
+                }
+                if let Ok(conn) = Arc::try_unwrap(conn) {
+                    if let Err(err) = conn.close().await {
+                        println!("Error: {}", err);
+                    }
                 }
             });
-
-            if count == total * num_iterations {
-                println!("Received all requests");
-                break;
-            }
         }
-        tx.send(()).unwrap();
+        println!("Received all requests");
     });
 
     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -60,37 +72,30 @@ async fn main() {
 
         println!("Client ready to use");
 
-        let (tx2, rx2) = oneshot::channel::<()>();
         let conn1 = conn.clone(); // Clone conn before moving into the task
         let client_task = task::spawn(async move {
-            let conn = conn1.clone();
             let mut count = 0;
             println!("Start receiving responses");
             loop {
-                // Clone conn before moving into the task
-
-                let msg = conn.recv_msg().await.unwrap();
+                let msg = conn1.recv_msg().await.unwrap();
                 // Handle data and response
                 count += 1;
-                let data = msg.to_vec();
-                conn.release(msg).await;
-                // println!("Count: {}, Msg: {:?}", count, data);
+                drop(msg);
                 if count == total {
                     println!("Received all responses");
                     break;
                 }
             }
-
-            tx2.send(()).unwrap();
         });
 
-        let mut handles = vec![];
         println!("Start sending requests");
         let start = Instant::now();
 
+        let conn2 = conn.clone(); // Clone conn before moving into the task
         let send_task = task::spawn(async move {
+            let mut handles = vec![];
             for i in 0..total {
-                let conn = conn.clone(); // Clone conn before moving into the task
+                let conn = conn2.clone(); // Clone conn before moving into the task
                 let send_task = task::spawn(async move {
                     let data = i.to_be_bytes();
                     let data = &[std::io::IoSlice::new(&data)];
@@ -105,6 +110,9 @@ async fn main() {
 
                 handles.push(send_task);
             }
+            for handle in handles {
+                handle.await.unwrap();
+            }
         });
 
         tokio::try_join!(client_task, send_task).unwrap();
@@ -112,7 +120,7 @@ async fn main() {
         let elapsed = start.elapsed();
         let elapsed_secs = elapsed.as_secs_f64();
         let throughput = total as f64 / elapsed_secs;
-        let latency = elapsed / total as u32;
+        let latency = elapsed / total;
         let qps = total as f64 / elapsed_secs;
 
         total_elapsed += elapsed;
@@ -120,15 +128,19 @@ async fn main() {
         total_latency += latency;
         total_qps += qps;
 
-        rx2.await.unwrap();
+        // both tasks are done with their clones
+        match Arc::try_unwrap(conn) {
+            Ok(conn) => conn.close().await.unwrap(),
+            Err(_) => println!("conn is still in use"),
+        }
         println!("Client {} done", i + 1)
     }
 
-    // rx.await.unwrap();
+    server_task.await.unwrap();
 
-    let avg_elapsed = total_elapsed / num_iterations as u32;
+    let avg_elapsed = total_elapsed / num_iterations;
     let avg_throughput = total_throughput / num_iterations as f64;
-    let avg_latency = total_latency / num_iterations as u32;
+    let avg_latency = total_latency / num_iterations;
     let avg_qps = total_qps / num_iterations as f64;
 
     println!("Average Elapsed time: {:.2?}", avg_elapsed);
@@ -10,23 +10,19 @@ async fn main() {
 
     println!("client ready to use");
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    // tokio oneshot
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
     let conn1 = conn.clone();
     let total = 100000;
-    tokio::spawn(async move {
+    let recv_task = tokio::spawn(async move {
         let mut count = 0;
         println!("start recving");
         loop {
             let msg = conn1.recv_msg().await.unwrap();
             // handle data and response
             count += 1;
-            let data = msg.to_vec();
-            conn1.release(msg).await;
+            let data = msg.into_owned();
             // println!("count: {}, msg: {:?}", count, data);
             if count == total {
                 println!("recv response done");
-                tx.send(()).unwrap();
                 break;
             }
         }
@@ -49,9 +45,18 @@ async fn main() {
         }));
     }
 
-    rx.await.unwrap();
+    // the recv task holds a clone of the conn until it is done
+    recv_task.await.unwrap();
     let elapsed = start.elapsed();
     println!("elapsed: {:?}", elapsed);
 
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    match Arc::try_unwrap(conn) {
+        Ok(conn) => conn.close().await.unwrap(),
+        Err(_) => println!("conn is still in use"),
+    }
+
     println!("done");
 }
@@ -0,0 +1,107 @@
+//! cargo run --example recv_stress
+//!
+//! many tasks call recv_msg on one Conn and hold the messages for a while, so they
+//! are released out of order. every message must arrive once and untorn, and no two
+//! messages held at the same time may share bytes.
+
+use std::collections::HashSet;
+use std::io::IoSlice;
+use std::sync::{Arc, Mutex};
+
+use ibv::connection::conn::connect;
+use ibv::connection::server::Server;
+use ibv::Error;
+
+const TOTAL: u64 = 100_000;
+const RECEIVERS: usize = 8;
+
+#[tokio::main]
+async fn main() {
+    let server = tokio::spawn(async {
+        let mut server = Server::new("127.0.0.1:7778".to_owned()).await.unwrap();
+        let conn = Arc::new(server.accept().await.unwrap());
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        // the byte ranges of the messages being held
+        let held = Arc::new(Mutex::new(Vec::<(usize, usize)>::new()));
+        let mut receivers = vec![];
+        for _ in 0..RECEIVERS {
+            let conn = conn.clone();
+            let seen = seen.clone();
+            let held = held.clone();
+            receivers.push(tokio::spawn(async move {
+                loop {
+                    let msg = match conn.recv_msg().await {
+                        Ok(msg) => msg,
+                        Err(Error::Closed) => break,
+                        Err(err) => panic!("{}", err),
+                    };
+                    let range = (msg.as_ptr() as usize, msg.as_ptr() as usize + msg.len());
+                    {
+                        let mut held = held.lock().unwrap();
+                        for &(start, end) in held.iter() {
+                            assert!(
+                                range.1 <= start || end <= range.0,
+                                "two receivers hold the same bytes"
+                            );
+                        }
+                        held.push(range);
+                    }
+                    let id = check(&msg);
+                    assert!(seen.lock().unwrap().insert(id), "message {} twice", id);
+                    // hold some of the messages longer than the ones after them
+                    for _ in 0..id % 4 {
+                        tokio::task::yield_now().await;
+                    }
+                    held.lock().unwrap().retain(|held| *held != range);
+                    drop(msg);
+                    // the client can't have more messages in flight than it has acks
+                    conn.send_msg(&[IoSlice::new(&id.to_le_bytes())])
+                        .await
+                        .unwrap();
+                }
+            }));
+        }
+        for receiver in receivers {
+            receiver.await.unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len() as u64, TOTAL);
+        println!("{} messages received once each", TOTAL);
+        Arc::try_unwrap(conn).ok().unwrap().close().await.unwrap();
+    });
+
+    // let the server listen
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    let (send, recv) = connect("127.0.0.1:7778").await.unwrap().split();
+    let acks = tokio::spawn(async move {
+        for _ in 0..TOTAL {
+            recv.recv_msg().await.unwrap();
+        }
+        recv
+    });
+    for id in 0..TOTAL {
+        let msg = message(id);
+        send.send_msg(&[IoSlice::new(&msg)]).await.unwrap();
+    }
+    let recv = acks.await.unwrap();
+    send.reunite(recv).unwrap().close().await.unwrap();
+    server.await.unwrap();
+    println!("done");
+}
+
+// the id repeated, 1 to 512 times
+fn message(id: u64) -> Vec<u8> {
+    let repeat = (id % 512 + 1) as usize;
+    id.to_le_bytes().repeat(repeat)
+}
+
+// return the id of a message, panic if it is torn
+fn check(msg: &[u8]) -> u64 {
+    assert!(
+        !msg.is_empty() && msg.len() % 8 == 0,
+        "bad length {}",
+        msg.len()
+    );
+    let id = u64::from_le_bytes(msg[..8].try_into().unwrap());
+    assert_eq!(msg, message(id).as_slice(), "message {} is torn", id);
+    id
+}
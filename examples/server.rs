@@ -4,17 +4,22 @@
 use std::{io::IoSlice, sync::Arc};
 
 use ibv::connection::{conn::Conn, server::Server};
+use ibv::Error;
 extern crate tokio;
 
 #[tokio::main]
 async fn main() {
-    let mut server = Server::new("127.0.0.1:7777".to_owned()).await;
-    let conn = Arc::new(server.accept().await);
+    let mut server = Server::new("127.0.0.1:7777".to_owned()).await.unwrap();
+    let conn = Arc::new(server.accept().await.unwrap());
 
     println!("server ready to use");
 
     println!("start recving");
-    handle(conn).await;
+    handle(conn.clone()).await;
+    match Arc::try_unwrap(conn) {
+        Ok(conn) => conn.close().await.unwrap(),
+        Err(_) => println!("conn is still in use"),
+    }
 
     println!("done");
 }
@@ -22,21 +27,29 @@ async fn main() {
 // parse recv_msg and response
 pub async fn handle(conn: Arc<Conn>) {
     let mut count: u32 = 0;
+    let mut handles = vec![];
     loop {
-        let msg = conn.recv_msg().await.unwrap();
+        let msg = match conn.recv_msg().await {
+            Ok(msg) => msg,
+            // the client has closed the Conn
+            Err(Error::Closed) => break,
+            Err(err) => panic!("{}", err),
+        };
         // handle data and response
         count += 1;
-        let data = msg.to_vec();
-        conn.release(msg).await;
+        let data = msg.into_owned();
         let conn = conn.clone();
-        tokio::spawn(async move {
+        handles.push(tokio::spawn(async move {
             let response = count.to_be_bytes();
             let response = &[IoSlice::new(&response)];
             match conn.send_msg(response).await {
                 Ok(_) => (),
                 Err(err) => println!("err: {}", err),
             }
-        });
+        }));
         // println!("count: {}, msg: {:?}", count, data);
     }
+    for handle in handles {
+        handle.await.unwrap();
+    }
 }
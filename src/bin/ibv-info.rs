@@ -0,0 +1,118 @@
+//! cargo run --bin ibv-info -- [-d <device>] [--json]
+//!
+//! print the RDMA devices, ports, gids and device limits as seen by this crate.
+
+use std::process::exit;
+
+use ibv::types::device::{Device, DeviceInfo, PortInfo};
+
+fn usage() -> ! {
+    eprintln!("usage: ibv-info [-d <device>] [--json]");
+    exit(2);
+}
+
+fn main() {
+    let mut json = false;
+    let mut name = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" | "-j" => json = true,
+            "-d" | "--device" => name = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ => usage(),
+        }
+    }
+
+    let listed = match Device::list() {
+        Ok(listed) => listed,
+        Err(e) => {
+            eprintln!("ibv-info: {}", e);
+            exit(1);
+        }
+    };
+    let wanted = |device: &str| name.as_ref().map_or(true, |name| device == name.as_str());
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    let mut failed = false;
+    for device in listed {
+        match device {
+            Ok(device) if wanted(&device.name) => devices.push(device),
+            // the other devices are still printed
+            Err(e) if wanted(&e.name) => {
+                eprintln!("ibv-info: {}", e);
+                failed = true;
+            }
+            _ => {}
+        }
+    }
+    if devices.is_empty() {
+        if failed {
+            exit(1);
+        }
+        match name {
+            Some(name) => eprintln!("ibv-info: RDMA device {} not found", name),
+            None => eprintln!("ibv-info: no RDMA device found"),
+        }
+        exit(1);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices).unwrap());
+    } else {
+        for device in devices.iter() {
+            print_device(device);
+        }
+    }
+}
+
+fn print_device(device: &DeviceInfo) {
+    let caps = &device.caps;
+    println!("hca_id: {}", device.name);
+    println!("\ttransport:\t\t{:?}", device.transport);
+    println!("\tfw_ver:\t\t\t{}", caps.fw_ver);
+    println!("\tnode_guid:\t\t{}", guid(device.node_guid));
+    println!("\tphys_port_cnt:\t\t{}", caps.phys_port_cnt);
+    println!("\tmax_qp:\t\t\t{}", caps.max_qp);
+    println!("\tmax_qp_wr:\t\t{}", caps.max_qp_wr);
+    println!("\tmax_sge:\t\t{}", caps.max_sge);
+    println!("\tmax_cq:\t\t\t{}", caps.max_cq);
+    println!("\tmax_cqe:\t\t{}", caps.max_cqe);
+    println!("\tmax_mr:\t\t\t{}", caps.max_mr);
+    println!("\tmax_mr_size:\t\t{:#x}", caps.max_mr_size);
+    println!("\tmax_pd:\t\t\t{}", caps.max_pd);
+    println!("\tmax_srq:\t\t{}", caps.max_srq);
+    println!("\tmax_qp_rd_atom:\t\t{}", caps.max_qp_rd_atom);
+    println!("\tmax_qp_init_rd_atom:\t{}", caps.max_qp_init_rd_atom);
+    println!("\tatomic_cap:\t\t{:?}", caps.atomic);
+    for port in device.ports.iter() {
+        print_port(port);
+    }
+    println!();
+}
+
+fn print_port(port: &PortInfo) {
+    println!("\t\tport:\t{}", port.port);
+    println!("\t\t\tstate:\t\t{:?}", port.state);
+    println!("\t\t\tmax_mtu:\t{}", port.max_mtu.bytes());
+    println!("\t\t\tactive_mtu:\t{}", port.active_mtu.bytes());
+    println!("\t\t\tlink_layer:\t{:?}", port.link_layer);
+    println!("\t\t\tlid:\t\t{}", port.lid);
+    for gid in port.gids.iter() {
+        println!(
+            "\t\t\tGID[{:>3}]:\t{}\t{}",
+            gid.index,
+            gid.gid,
+            gid.gid_type.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+// xxxx:xxxx:xxxx:xxxx like ibv_devinfo
+fn guid(guid: u64) -> String {
+    let hex = format!("{:016x}", guid);
+    hex.as_bytes()
+        .chunks(4)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,622 @@
+//! connection setup through librdmacm: rdma_cm resolves the route and computes the
+//! QP attributes, and the recv buffers travel in the private data of the connect
+//! request and reply, so no bootstrap channel is needed.
+//!
+//! the QPs are still created by the reactor on its own device, rdma_cm only moves
+//! them through RTR and RTS.
+
+use super::config::ConnConfig;
+use super::conn::{within, Conn, SETUP};
+use super::reactor::Reactor;
+use crate::error::{Error, Result};
+use crate::types::{
+    context::Context,
+    cq::register_fd,
+    device::DeviceSelector,
+    handshake::{features, BufferInfo, Limits, Negotiated, MAGIC, PROTOCOL_VERSION},
+    mr::{RecvBuffer, RemoteMR},
+    qp::QP,
+};
+use log::{error, info, warn};
+use rdma_sys::rdma_cm_event_type::{
+    RDMA_CM_EVENT_ADDR_RESOLVED, RDMA_CM_EVENT_CONNECT_REQUEST, RDMA_CM_EVENT_CONNECT_RESPONSE,
+    RDMA_CM_EVENT_ESTABLISHED, RDMA_CM_EVENT_REJECTED, RDMA_CM_EVENT_ROUTE_RESOLVED,
+};
+use rdma_sys::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::{io, mem, ptr};
+use tokio::io::unix::AsyncFd;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc::Sender;
+
+// for rdma_resolve_addr and rdma_resolve_route
+const RESOLVE_TIMEOUT_MS: i32 = 2000;
+// the private data of a connect request is the smallest, 56 bytes on IB and RoCE
+const MAX_PRIVATE_DATA: usize = 56;
+const LISTEN_BACKLOG: i32 = 128;
+// recovery resyncs over the bootstrap channel, which a rdma_cm Conn doesn't have
+const CM_FEATURES: u64 = features::ALL & !features::RECOVERY;
+// the private data carries the features in 16 bits
+const _: () = assert!(CM_FEATURES <= u16::MAX as u64);
+
+// client side: resolve addr with rdma_cm and connect on the device it resolves to
+pub(crate) async fn connect(addr: &str, config: &ConnConfig) -> Result<Conn> {
+    within(config.timeout, SETUP, async {
+        let id = CmId::resolve(addr).await?;
+        let reactor = Reactor::new(Context::open(&id.selector(&config.device))?, config)?;
+        establish(&reactor, id).await
+    })
+    .await
+}
+
+// client side on a shared reactor, addr must resolve to the device of the reactor
+pub(crate) async fn connect_to(reactor: &Arc<Reactor>, addr: &str) -> Result<Conn> {
+    within(reactor.config().timeout, SETUP, async {
+        let id = CmId::resolve(addr).await?;
+        id.check_device(reactor.context())?;
+        establish(reactor, id).await
+    })
+    .await
+}
+
+async fn establish(reactor: &Arc<Reactor>, mut id: CmId) -> Result<Conn> {
+    let config = reactor.config();
+    let mut qp = reactor.create_qp()?;
+    qp.init()?;
+    let local = config.limits();
+    let (mut recv_buf, tx) = qp.recv_buffer(&local)?;
+    let private = CmPrivate::new(&recv_buf, local).encode()?;
+    id.connect(&qp, config, &private)?;
+    let event = id.channel.expect(RDMA_CM_EVENT_CONNECT_RESPONSE).await?;
+    let remote = CmPrivate::decode(&event.private_data)?;
+    let limits = settle(&mut qp, &local, &remote);
+    id.transition(&qp, ibv_qp_state::IBV_QPS_RTR)?;
+    id.transition(&qp, ibv_qp_state::IBV_QPS_RTS)?;
+    // the remote side is waiting for the RTU
+    id.establish()?;
+    recv_buf.set_release_threshold(config.release_threshold);
+    let conn = Conn::new(
+        Arc::new(qp),
+        recv_buf,
+        remote.buffer.mr,
+        tx,
+        reactor.clone(),
+        limits,
+    )
+    .await?;
+    Ok(conn.with_cm(id))
+}
+
+// server side: a rdma_cm id listening on addr
+pub(crate) struct Listener {
+    id: CmId,
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &str) -> Result<Self> {
+        let addr = resolve_host(addr).await?;
+        let id = CmId::new()?;
+        let mut addr = sockaddr(addr);
+        if unsafe { rdma_bind_addr(id.id, addr_ptr(&mut addr)) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        if unsafe { rdma_listen(id.id, LISTEN_BACKLOG) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        Ok(Self { id })
+    }
+
+    // the device the listener is bound to, or device for a wildcard address
+    pub(crate) fn selector(&self, device: &DeviceSelector) -> DeviceSelector {
+        self.id.selector(device)
+    }
+}
+
+// server side accept the connect requests on the listener
+pub(crate) async fn run(listener: Listener, reactor: Arc<Reactor>, sender: Sender<Conn>) {
+    loop {
+        let event = match listener.id.channel.next().await {
+            Ok(event) => event,
+            Err(e) => {
+                error!("rdma_cm listener error: {}", e);
+                break;
+            }
+        };
+        if event.kind != RDMA_CM_EVENT_CONNECT_REQUEST {
+            warn!("rdma_cm listener ignores {}", event_name(event.kind));
+            continue;
+        }
+        // the events of the new id go to its own channel from now on
+        let id = match CmId::migrate(event.id) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("rdma_cm migrate id error: {}", e);
+                continue;
+            }
+        };
+        let peer = id.peer();
+        info!("New rdma_cm connection from {}", peer);
+        if sender.is_closed() {
+            break;
+        }
+        // a slow or silent peer only holds up its own setup, and a failed one
+        // only rejects this connection
+        let reactor = reactor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let conn = match accept(&reactor, id, event.private_data).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("server accept {} error: {}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = sender.send(conn).await {
+                error!("server send conn error: {}", e);
+            }
+        });
+    }
+}
+
+async fn accept(reactor: &Arc<Reactor>, mut id: CmId, private_data: Vec<u8>) -> Result<Conn> {
+    match within(
+        reactor.config().timeout,
+        SETUP,
+        accept_on(reactor, &mut id, &private_data),
+    )
+    .await
+    {
+        Ok(conn) => Ok(conn.with_cm(id)),
+        Err(e) => {
+            // an accepted id can't be rejected, it is disconnected when it drops
+            if !id.connected {
+                id.reject();
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn accept_on(reactor: &Arc<Reactor>, id: &mut CmId, private_data: &[u8]) -> Result<Conn> {
+    let config = reactor.config();
+    id.check_device(reactor.context())?;
+    let remote = CmPrivate::decode(private_data)?;
+    let mut qp = reactor.create_qp()?;
+    qp.init()?;
+    let local = config.limits();
+    let (mut recv_buf, tx) = qp.recv_buffer(&local)?;
+    let limits = settle(&mut qp, &local, &remote);
+    // the request carries the qpn and PSN of the remote side, so the QP is
+    // ready before the reply goes out
+    id.transition(&qp, ibv_qp_state::IBV_QPS_RTR)?;
+    id.transition(&qp, ibv_qp_state::IBV_QPS_RTS)?;
+    let private = CmPrivate::new(&recv_buf, local).encode()?;
+    id.accept(&qp, config, &private)?;
+    id.channel.expect(RDMA_CM_EVENT_ESTABLISHED).await?;
+    recv_buf.set_release_threshold(config.release_threshold);
+    Conn::new(
+        Arc::new(qp),
+        recv_buf,
+        remote.buffer.mr,
+        tx,
+        reactor.clone(),
+        limits,
+    )
+    .await
+}
+
+fn settle(qp: &mut QP, local: &Limits, remote: &CmPrivate) -> Negotiated {
+    let features = CM_FEATURES & remote.features as u64;
+    qp.set_features(features);
+    Negotiated::settle(
+        local,
+        &remote.buffer.limits,
+        qp.config().cap.max_send_wr,
+        features,
+    )
+}
+
+// what one side puts in the private data of the connect request or reply
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CmPrivate {
+    magic: u32,
+    version: u16,
+    features: u16,
+    buffer: BufferInfo,
+}
+
+impl CmPrivate {
+    fn new(recv_buf: &RecvBuffer, limits: Limits) -> Self {
+        Self {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            features: CM_FEATURES as u16,
+            buffer: BufferInfo {
+                mr: RemoteMR::from_mr(recv_buf.mr()),
+                limits,
+            },
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let data = bincode::serialize(self)?;
+        if data.len() > MAX_PRIVATE_DATA {
+            return Err(Error::Protocol(format!(
+                "private data of {} bytes exceeds {}",
+                data.len(),
+                MAX_PRIVATE_DATA
+            )));
+        }
+        Ok(data)
+    }
+
+    // the private data is padded by the CM, the trailing zeros are ignored
+    fn decode(data: &[u8]) -> Result<Self> {
+        let private: Self = bincode::deserialize(data)?;
+        if private.magic != MAGIC {
+            return Err(Error::Protocol(format!(
+                "bad magic {:#010x}, the remote side is not an ibv peer",
+                private.magic
+            )));
+        }
+        if private.version != PROTOCOL_VERSION {
+            return Err(Error::Protocol(format!(
+                "protocol version {} is not supported, expect {}",
+                private.version, PROTOCOL_VERSION
+            )));
+        }
+        private.buffer.validate()?;
+        Ok(private)
+    }
+}
+
+// a rdma_cm_id and the event channel it reports to
+pub(crate) struct CmId {
+    id: *mut rdma_cm_id,
+    connected: bool,
+    // destroyed after the id
+    channel: Channel,
+}
+
+unsafe impl Send for CmId {}
+unsafe impl Sync for CmId {}
+
+impl CmId {
+    fn new() -> Result<Self> {
+        let channel = Channel::new()?;
+        let mut id = ptr::null_mut();
+        let ret = unsafe {
+            rdma_create_id(
+                channel.inner,
+                &mut id,
+                ptr::null_mut(),
+                rdma_port_space::RDMA_PS_TCP,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        Ok(Self {
+            id,
+            connected: false,
+            channel,
+        })
+    }
+
+    // take over the id of a connect request
+    fn migrate(id: *mut rdma_cm_id) -> Result<Self> {
+        let channel = match Channel::new() {
+            Ok(channel) => channel,
+            Err(e) => {
+                unsafe { rdma_reject(id, ptr::null(), 0) };
+                unsafe { rdma_destroy_id(id) };
+                return Err(e);
+            }
+        };
+        if unsafe { rdma_migrate_id(id, channel.inner) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { rdma_reject(id, ptr::null(), 0) };
+            unsafe { rdma_destroy_id(id) };
+            return Err(Error::Cm(err));
+        }
+        Ok(Self {
+            id,
+            connected: false,
+            channel,
+        })
+    }
+
+    // resolve the address and the route to addr
+    async fn resolve(addr: &str) -> Result<Self> {
+        let addr = resolve_host(addr).await?;
+        let id = Self::new()?;
+        let mut dst = sockaddr(addr);
+        let ret = unsafe {
+            rdma_resolve_addr(
+                id.id,
+                ptr::null_mut(),
+                addr_ptr(&mut dst),
+                RESOLVE_TIMEOUT_MS,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        id.channel.expect(RDMA_CM_EVENT_ADDR_RESOLVED).await?;
+        if unsafe { rdma_resolve_route(id.id, RESOLVE_TIMEOUT_MS) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        id.channel.expect(RDMA_CM_EVENT_ROUTE_RESOLVED).await?;
+        Ok(id)
+    }
+
+    // the device and port the id is bound to, the gid is left to device
+    fn selector(&self, device: &DeviceSelector) -> DeviceSelector {
+        match self.device_name() {
+            Some(name) => DeviceSelector {
+                name: Some(name),
+                port: unsafe { (*self.id).port_num },
+                gid: device.gid.clone(),
+            },
+            None => device.clone(),
+        }
+    }
+
+    fn device_name(&self) -> Option<String> {
+        let verbs = unsafe { (*self.id).verbs };
+        if verbs.is_null() {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(ibv_get_device_name((*verbs).device)) };
+        Some(name.to_string_lossy().into_owned())
+    }
+
+    fn check_device(&self, ctx: &Context) -> Result<()> {
+        let local = ctx.device().name();
+        match self.device_name() {
+            Some(name) if name != local => Err(Error::InvalidConfig(format!(
+                "the route goes through {}, the reactor is on {}",
+                name, local
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn peer(&self) -> String {
+        let addr = unsafe { rdma_get_peer_addr(self.id) };
+        if addr.is_null() {
+            return "unknown".to_owned();
+        }
+        from_sockaddr(addr.cast()).map_or_else(|| "unknown".to_owned(), |addr| addr.to_string())
+    }
+
+    // move qp to state with the attributes rdma_cm computed from the route
+    fn transition(&self, qp: &QP, state: ibv_qp_state::Type) -> Result<()> {
+        let mut attr = unsafe { mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = state;
+        let mut mask = 0;
+        if unsafe { rdma_init_qp_attr(self.id, &mut attr, &mut mask) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        qp.transition(&mut attr, ibv_qp_attr_mask(mask as u32))
+    }
+
+    fn connect(&self, qp: &QP, config: &ConnConfig, private: &[u8]) -> Result<()> {
+        let mut param = conn_param(qp, config, private);
+        if unsafe { rdma_connect(self.id, &mut param) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn establish(&mut self) -> Result<()> {
+        if unsafe { rdma_establish(self.id) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        self.connected = true;
+        Ok(())
+    }
+
+    fn accept(&mut self, qp: &QP, config: &ConnConfig, private: &[u8]) -> Result<()> {
+        let mut param = conn_param(qp, config, private);
+        if unsafe { rdma_accept(self.id, &mut param) } != 0 {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        self.connected = true;
+        Ok(())
+    }
+
+    fn reject(&self) {
+        if unsafe { rdma_reject(self.id, ptr::null(), 0) } != 0 {
+            warn!("rdma_reject error: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+impl Drop for CmId {
+    fn drop(&mut self) {
+        unsafe {
+            if self.connected {
+                rdma_disconnect(self.id);
+            }
+            rdma_destroy_id(self.id);
+        }
+    }
+}
+
+fn conn_param(qp: &QP, config: &ConnConfig, private: &[u8]) -> rdma_conn_param {
+    let mut param = unsafe { mem::zeroed::<rdma_conn_param>() };
+    param.private_data = private.as_ptr().cast();
+    param.private_data_len = private.len() as u8;
+    param.responder_resources = config.qp.max_dest_rd_atomic;
+    param.initiator_depth = config.qp.max_rd_atomic;
+    param.retry_count = config.qp.retry_cnt;
+    param.rnr_retry_count = config.qp.rnr_retry;
+    param.qp_num = qp.qpn();
+    param
+}
+
+// an event copied out of rdma_cm, it is acked as soon as it is read
+struct Event {
+    kind: rdma_cm_event_type::Type,
+    status: i32,
+    id: *mut rdma_cm_id,
+    private_data: Vec<u8>,
+}
+
+unsafe impl Send for Event {}
+
+struct Channel {
+    inner: *mut rdma_event_channel,
+    fd: Option<AsyncFd<RawFd>>,
+}
+
+unsafe impl Send for Channel {}
+unsafe impl Sync for Channel {}
+
+impl Channel {
+    fn new() -> Result<Self> {
+        let inner = unsafe { rdma_create_event_channel() };
+        if inner.is_null() {
+            return Err(Error::Cm(io::Error::last_os_error()));
+        }
+        let mut channel = Self { inner, fd: None };
+        // rdma_get_cm_event must not block the runtime
+        channel.fd = Some(register_fd(unsafe { (*inner).fd }).map_err(Error::Cm)?);
+        Ok(channel)
+    }
+
+    async fn next(&self) -> Result<Event> {
+        let fd = match &self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::Disconnected),
+        };
+        loop {
+            let mut guard = fd.readable().await?;
+            let mut event = ptr::null_mut();
+            if unsafe { rdma_get_cm_event(self.inner, &mut event) } == 0 {
+                let copied = unsafe { copy_event(event) };
+                unsafe { rdma_ack_cm_event(event) };
+                return Ok(copied);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(Error::Cm(err));
+            }
+            guard.clear_ready();
+        }
+    }
+
+    // the next event must be kind
+    async fn expect(&self, kind: rdma_cm_event_type::Type) -> Result<Event> {
+        let event = self.next().await?;
+        if event.kind == kind {
+            return Ok(event);
+        }
+        let err_kind = if event.kind == RDMA_CM_EVENT_REJECTED {
+            io::ErrorKind::ConnectionRefused
+        } else {
+            io::ErrorKind::Other
+        };
+        Err(Error::Cm(io::Error::new(
+            err_kind,
+            format!(
+                "expect {}, got {} with status {}",
+                event_name(kind),
+                event_name(event.kind),
+                event.status
+            ),
+        )))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // deregister the fd before the channel is destroyed
+        self.fd.take();
+        unsafe {
+            rdma_destroy_event_channel(self.inner);
+        }
+    }
+}
+
+unsafe fn copy_event(event: *mut rdma_cm_event) -> Event {
+    let conn = &(*event).param.conn;
+    let private_data = if conn.private_data.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(
+            conn.private_data as *const u8,
+            conn.private_data_len as usize,
+        )
+        .to_vec()
+    };
+    Event {
+        kind: (*event).event,
+        status: (*event).status,
+        id: (*event).id,
+        private_data,
+    }
+}
+
+fn event_name(kind: rdma_cm_event_type::Type) -> String {
+    unsafe { CStr::from_ptr(rdma_event_str(kind)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+async fn resolve_host(addr: &str) -> Result<SocketAddr> {
+    lookup_host(addr).await?.next().ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} resolves to no address", addr),
+        ))
+    })
+}
+
+fn sockaddr(addr: SocketAddr) -> libc::sockaddr_storage {
+    let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+        }
+    }
+    storage
+}
+
+fn addr_ptr(storage: &mut libc::sockaddr_storage) -> *mut sockaddr {
+    storage as *mut _ as *mut sockaddr
+}
+
+fn from_sockaddr(addr: *const libc::sockaddr) -> Option<SocketAddr> {
+    unsafe {
+        match (*addr).sa_family as i32 {
+            libc::AF_INET => {
+                let sin = &*(addr as *const libc::sockaddr_in);
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into();
+                Some(SocketAddr::new(ip, u16::from_be(sin.sin_port)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = &*(addr as *const libc::sockaddr_in6);
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr).into();
+                Some(SocketAddr::new(ip, u16::from_be(sin6.sin6_port)))
+            }
+            _ => None,
+        }
+    }
+}
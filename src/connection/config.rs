@@ -0,0 +1,301 @@
+use super::daemon::PollingStrategy;
+use super::poller::PollingThread;
+use crate::error::{Error, Result};
+use crate::types::{
+    default::{
+        DEFAULT_MAX_MSG_SIZE, DEFAULT_RECV_BUFFER_SIZE, DEFAULT_RQE_COUNT,
+        DEFAULT_SEND_BUFFER_SIZE, DEFAULT_TIMEOUT, MIN_LENGTH_TO_NOTIFY_RELEASE,
+    },
+    device::DeviceSelector,
+    handshake::Limits,
+    qp::QPConfig,
+};
+use std::time::Duration;
+
+// the imm carries a position in the ring plus one, and u32::MAX closes the Conn
+const MAX_BUFFER_SIZE: usize = u32::MAX as usize - 2;
+
+// configuration of a Conn, used by connect_with and Server::with_config
+#[derive(Debug, Clone)]
+pub struct ConnConfig {
+    pub polling: PollingStrategy,
+    // poll on a dedicated OS thread instead of a tokio task per CQ
+    pub polling_thread: Option<PollingThread>,
+    // number of CQs shared by the Conns on a device
+    pub cq_shards: usize,
+    // the device, port and gid the Conns use
+    pub device: DeviceSelector,
+    // caps and path attributes of the QPs
+    pub qp: QPConfig,
+    // the ring the remote side writes into, registered per Conn
+    pub recv_buffer_size: usize,
+    // the ring messages are copied into before sending, registered per Conn
+    pub send_buffer_size: usize,
+    // the largest message, the remote side may settle on less
+    pub max_msg_size: u32,
+    // RQEs kept posted, bounds the writes the remote side has in flight
+    pub rqe_count: u32,
+    // messages up to this size are sent inline, 0 for none
+    pub inline_threshold: u32,
+    // released bytes are told to the remote side once they add up to this
+    pub release_threshold: u32,
+    // for connecting, the handshake and draining the QP, None for no timeout
+    pub timeout: Option<Duration>,
+    // set up the Conns with librdmacm instead of the tcp handshake
+    pub rdma_cm: bool,
+}
+
+impl Default for ConnConfig {
+    fn default() -> Self {
+        Self {
+            polling: PollingStrategy::default(),
+            polling_thread: None,
+            cq_shards: 1,
+            device: DeviceSelector::default(),
+            qp: QPConfig::default(),
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            send_buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
+            rqe_count: DEFAULT_RQE_COUNT,
+            inline_threshold: 0,
+            release_threshold: MIN_LENGTH_TO_NOTIFY_RELEASE,
+            timeout: Some(DEFAULT_TIMEOUT),
+            rdma_cm: false,
+        }
+    }
+}
+
+impl ConnConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn polling(mut self, polling: PollingStrategy) -> Self {
+        self.polling = polling;
+        self
+    }
+
+    pub fn polling_thread(mut self, polling_thread: PollingThread) -> Self {
+        self.polling_thread = Some(polling_thread);
+        self
+    }
+
+    pub fn cq_shards(mut self, cq_shards: usize) -> Self {
+        self.cq_shards = cq_shards;
+        self
+    }
+
+    pub fn device(mut self, device: DeviceSelector) -> Self {
+        self.device = device;
+        self
+    }
+
+    pub fn qp(mut self, qp: QPConfig) -> Self {
+        self.qp = qp;
+        self
+    }
+
+    pub fn buffer_size(mut self, send_buffer_size: usize, recv_buffer_size: usize) -> Self {
+        self.send_buffer_size = send_buffer_size;
+        self.recv_buffer_size = recv_buffer_size;
+        self
+    }
+
+    pub fn max_msg_size(mut self, max_msg_size: u32) -> Self {
+        self.max_msg_size = max_msg_size;
+        self
+    }
+
+    pub fn rqe_count(mut self, rqe_count: u32) -> Self {
+        self.rqe_count = rqe_count;
+        self
+    }
+
+    pub fn inline_threshold(mut self, inline_threshold: u32) -> Self {
+        self.inline_threshold = inline_threshold;
+        self
+    }
+
+    pub fn release_threshold(mut self, release_threshold: u32) -> Self {
+        self.release_threshold = release_threshold;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // wait for the remote side as long as it takes
+    pub fn no_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+
+    pub fn rdma_cm(mut self, rdma_cm: bool) -> Self {
+        self.rdma_cm = rdma_cm;
+        self
+    }
+
+    // checked by Reactor::new, the Conns of a reactor are set up with its config
+    pub fn validate(&self) -> Result<()> {
+        let check = |ok: bool, msg: String| {
+            if ok {
+                Ok(())
+            } else {
+                Err(Error::InvalidConfig(msg))
+            }
+        };
+        check(
+            self.cq_shards > 0,
+            "cq_shards must be at least 1".to_owned(),
+        )?;
+        for (name, size) in [
+            ("send_buffer_size", self.send_buffer_size),
+            ("recv_buffer_size", self.recv_buffer_size),
+        ] {
+            check(
+                size > 0 && size <= MAX_BUFFER_SIZE,
+                format!("{} {} is not in 1..={}", name, size, MAX_BUFFER_SIZE),
+            )?;
+        }
+        // a message must leave room in either ring, or wrapping around never finds space
+        let half = self.send_buffer_size.min(self.recv_buffer_size) / 2;
+        check(
+            self.max_msg_size > 0 && self.max_msg_size as usize <= half,
+            format!(
+                "max_msg_size {} is not in 1..={}, half of the smaller buffer",
+                self.max_msg_size, half
+            ),
+        )?;
+        check(
+            self.rqe_count > 0 && self.rqe_count <= self.qp.cap.max_recv_wr(),
+            format!(
+                "rqe_count {} is not in 1..={}, max_recv_wr of the qp",
+                self.rqe_count,
+                self.qp.cap.max_recv_wr()
+            ),
+        )?;
+        // the remote side waits for the release once its writes fill half the ring
+        check(
+            self.release_threshold as usize <= self.recv_buffer_size / 2,
+            format!(
+                "release_threshold {} exceeds half of recv_buffer_size {}",
+                self.release_threshold, self.recv_buffer_size
+            ),
+        )?;
+        match &self.polling_thread {
+            Some(polling_thread) => polling_thread.validate(),
+            None => Ok(()),
+        }
+    }
+
+    // what this side advertises in the handshake
+    pub(crate) fn limits(&self) -> Limits {
+        Limits {
+            recv_buffer_size: self.recv_buffer_size as u64,
+            send_buffer_size: self.send_buffer_size as u64,
+            max_msg_size: self.max_msg_size,
+            rqe_count: self.rqe_count,
+            max_inline: self.inline_threshold,
+        }
+    }
+
+    // the CQ need a completion channel only when a tokio task waits on it
+    pub(crate) fn with_channel(&self) -> bool {
+        self.polling_thread.is_none() && self.polling.with_channel()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_invalid(config: ConnConfig, expect: &str) {
+        match config.validate() {
+            Err(Error::InvalidConfig(msg)) => assert!(msg.contains(expect), "{}", msg),
+            other => panic!("expect an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert!(ConnConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn at_the_limits() {
+        let config = ConnConfig::new()
+            .buffer_size(MAX_BUFFER_SIZE, 2048)
+            .max_msg_size(1024)
+            .rqe_count(QPConfig::default().cap.max_recv_wr())
+            .release_threshold(1024);
+        assert!(config.validate().is_ok());
+        assert!(ConnConfig::new()
+            .buffer_size(2, 2)
+            .max_msg_size(1)
+            .rqe_count(1)
+            .release_threshold(0)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn buffers_fit_the_imm() {
+        assert_invalid(
+            ConnConfig::new().buffer_size(0, 1 << 20),
+            "send_buffer_size",
+        );
+        assert_invalid(
+            ConnConfig::new().buffer_size(1 << 20, 0),
+            "recv_buffer_size",
+        );
+        assert_invalid(
+            ConnConfig::new().buffer_size(MAX_BUFFER_SIZE + 1, 1 << 20),
+            "send_buffer_size",
+        );
+        assert_invalid(
+            ConnConfig::new().buffer_size(1 << 20, 4 << 30),
+            "recv_buffer_size",
+        );
+    }
+
+    #[test]
+    fn messages_fit_half_the_buffers() {
+        assert_invalid(ConnConfig::new().max_msg_size(0), "max_msg_size");
+        let config = ConnConfig::new()
+            .buffer_size(1 << 20, 4096)
+            .max_msg_size(2049);
+        assert_invalid(config.clone(), "max_msg_size");
+        assert!(config
+            .max_msg_size(2048)
+            .release_threshold(0)
+            .validate()
+            .is_ok());
+        assert_invalid(
+            ConnConfig::new()
+                .buffer_size(4096, 1 << 20)
+                .max_msg_size(2049),
+            "max_msg_size",
+        );
+    }
+
+    #[test]
+    fn rqes_and_release() {
+        assert_invalid(ConnConfig::new().rqe_count(0), "rqe_count");
+        let max_recv_wr = QPConfig::default().cap.max_recv_wr();
+        assert_invalid(ConnConfig::new().rqe_count(max_recv_wr + 1), "rqe_count");
+        assert_invalid(
+            ConnConfig::new()
+                .buffer_size(1 << 20, 4096)
+                .max_msg_size(1024)
+                .release_threshold(2049),
+            "release_threshold",
+        );
+        assert_invalid(ConnConfig::new().cq_shards(0), "cq_shards");
+        assert_invalid(
+            ConnConfig::new().polling_thread(PollingThread::new().pin_to(usize::MAX)),
+            "cpu",
+        );
+    }
+}
@@ -1,95 +1,358 @@
 //! interfaec Conn:
 //!
 //!     1.send_msg(data: &[IoSlice]) -> Result<()>
-//!     2.recv_msg() -> Result<&[u8]>
+//!     2.recv_msg() -> Result<RecvGuard>
+//!     3.close() -> Result<()>
+//!     4.split() -> (SendHalf, RecvHalf)
+//!
+//! errors are reported as `ibv::Error`.
 
-use crate::types::{
-    default::{DEFAULT_RQE_COUNT, MAX_QP_WR},
-    device::{default_device, Device},
-    qp::QPCap,
-};
+use crate::error::{Error, Result};
+use crate::types::context::Context;
 use log::{error, info};
-use std::{io::IoSlice, sync::Arc};
+use std::fmt;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard, OnceLock};
+use std::time::Duration;
 use std::{
-    io::Result,
-    sync::atomic::{AtomicI32, Ordering},
+    io::{self, IoSlice},
+    sync::Arc,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::mpsc::error::TryRecvError,
 };
 
-use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
-use tokio::{
-    io,
-    sync::mpsc::{Receiver, Sender},
-};
+use tokio::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Mutex, Notify};
 
 use crate::types::{
-    mr::{RecvBuffer, RemoteBufManager, RemoteMR, SendBuffer},
+    bootstrap::{Bootstrap, TcpBootstrap},
+    completion::{wait_until, Completion},
+    handshake::{features, read_frame, write_frame, Hello, Kind, Negotiated, Resync},
+    mr::{LocalBuf, RecvBuffer, RemoteBufManager, RemoteMR, SendBuffer},
     qp::QP,
 };
 
-use super::daemon::polling;
+use super::cm::{self, CmId};
+use super::config::ConnConfig;
+use super::daemon::{Dispatcher, PollingReport};
+use super::reactor::Reactor;
+use super::state::ConnState;
 
-// RQE of the remote side might be shortage, so we need to limit the number of sending
-// test shows that the max sending is 1023 in RoCE that equal to the max RQE of the remote side
-pub static MAX_SENDING: i32 = DEFAULT_RQE_COUNT as i32;
+// the imm of the zero-length write that closes a direction, a release length never
+// reaches it since the ring is smaller than 4GiB
+const CLOSE_IMM: u32 = u32::MAX;
 
 pub struct Conn {
-    recv_buf: RecvBuffer,
+    send: SendHalf,
+    recv: RecvHalf,
+}
+
+// the state both directions of a Conn use. the QP is retired when the last half
+// drops, the buffers of the halves are parked here until then.
+struct Link {
+    qp: Arc<QP>,
+    // the remote ring, allocated by the sends and freed by the released
+    // positions the received messages carry
+    allocator: RemoteBufManager,
+    // writes in flight, each received message frees a RQE of the remote side
     sending: AtomicI32,
+    // woken when sending drops, the sends waiting for a RQE check again
+    credit: Notify,
+    // set by the daemon when a work completion fails
+    state: Arc<ConnState>,
+    // polls the CQ of qp and dispatches the wcs to this Conn
+    reactor: Arc<Reactor>,
+    // kept to register the QP again after recover
+    tx: UnboundedSender<(u32, u32)>,
+    // settled with the remote side during connection setup
+    limits: Negotiated,
+    // the buffers of the dropped halves, deregistered after the QP is flushed
+    parked: StdMutex<Vec<Box<dyn Send>>>,
+    // the rdma_cm id the Conn was set up with, disconnected on drop
+    cm: OnceLock<CmId>,
+}
+
+// the sending direction of a Conn
+pub struct SendHalf {
+    link: Arc<Link>,
     // protect three below: remote_buf alloc, sending and release.
     lock: Mutex<()>,
-    allocator: RemoteBufManager,
-    send_buf: SendBuffer,
-    qp: Arc<QP>,
-    release: (Sender<u32>, MyReceiver<u32>),
-    pub daemon: JoinHandle<()>,
+    send_buf: ManuallyDrop<SendBuffer>,
+    // the released positions to tell the remote side, sent by the RecvHalf
+    release: StdMutex<UnboundedReceiver<u32>>,
+}
+
+// the receiving direction of a Conn
+pub struct RecvHalf {
+    link: Arc<Link>,
+    recv_buf: ManuallyDrop<RecvBuffer>,
+    // the released positions of the dropped RecvGuards, for the SendHalf
+    release: UnboundedSender<u32>,
 }
 
-unsafe impl Send for Conn {}
-unsafe impl Sync for Conn {}
+// the halves move into the tasks of a pipeline, the compiler checks they can
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<Conn>();
+    check::<SendHalf>();
+    check::<RecvHalf>();
+}
 
 impl Conn {
     pub async fn new(
         qp: Arc<QP>,
         recv_buf: RecvBuffer,
         remote_mr: RemoteMR,
-        tx: Sender<(u32, u32)>,
-    ) -> Self {
-        let allocator = RemoteBufManager::new(remote_mr);
-        let send_buf = SendBuffer::new(&qp.pd).await;
+        tx: UnboundedSender<(u32, u32)>,
+        reactor: Arc<Reactor>,
+        limits: Negotiated,
+    ) -> Result<Self> {
+        let allocator = RemoteBufManager::new(remote_mr, limits.max_sending);
+        let send_buf = SendBuffer::new(
+            &qp.ctx,
+            limits.send_buffer_size as usize,
+            limits.max_sending,
+        )
+        .await?;
         let qp_c = qp.clone();
         // add sufficient RQE, maybe use SRQ to notify adding RQE
-        for _ in 0..DEFAULT_RQE_COUNT {
-            qp.post_null_recv();
+        for _ in 0..limits.rqe_count {
+            qp.post_null_recv()?;
         }
-        let daemon = tokio::spawn(polling(qp_c, tx));
-        let (tx, rx) = tokio::sync::mpsc::channel(DEFAULT_RQE_COUNT as usize);
-        let release = (tx, MyReceiver::new(rx));
-        Conn {
+        let state = Arc::new(ConnState::new());
+        reactor.register(qp.qpn(), Dispatcher::new(qp_c, tx.clone(), state.clone()));
+        let (release_tx, release_rx) = tokio::sync::mpsc::unbounded_channel();
+        let link = Arc::new(Link {
             qp,
             allocator,
             sending: AtomicI32::new(0),
-            lock: Mutex::new(()),
-            send_buf,
-            daemon,
-            release,
-            recv_buf,
-        }
+            credit: Notify::new(),
+            state,
+            reactor,
+            tx,
+            limits,
+            parked: StdMutex::new(Vec::new()),
+            cm: OnceLock::new(),
+        });
+        Ok(Conn {
+            send: SendHalf {
+                link: link.clone(),
+                lock: Mutex::new(()),
+                send_buf: ManuallyDrop::new(send_buf),
+                release: StdMutex::new(release_rx),
+            },
+            recv: RecvHalf {
+                link,
+                recv_buf: ManuallyDrop::new(recv_buf),
+                release: release_tx,
+            },
+        })
+    }
+
+    // the Conn was set up by rdma_cm, keep the id connected as long as the Conn
+    pub(crate) fn with_cm(self, cm: CmId) -> Self {
+        let _ = self.send.link.cm.set(cm);
+        self
     }
 
     pub fn qp(&self) -> Arc<QP> {
-        self.qp.clone()
+        self.send.link.qp.clone()
+    }
+
+    // the message size, in flight writes and features settled with the remote side
+    pub fn limits(&self) -> &Negotiated {
+        self.send.limits()
+    }
+
+    // buffers registered in this Context can be sent with send_registered
+    pub fn context(&self) -> &Arc<Context> {
+        self.send.context()
+    }
+
+    // the error that failed the connection, if any
+    pub fn error(&self) -> Option<Error> {
+        self.send.error()
+    }
+
+    // time the daemon has spent in each polling phase
+    pub fn polling_report(&self) -> PollingReport {
+        self.send.link.reactor.report()
+    }
+
+    // split the Conn into its two directions, e.g. for a producer and a consumer
+    // task. they share no lock, and the Conn lives until both are dropped.
+    pub fn split(self) -> (SendHalf, RecvHalf) {
+        (self.send, self.recv)
+    }
+
+    pub async fn send_msg(&self, msg: &[IoSlice<'_>]) -> Result<()> {
+        self.send.send_msg(msg).await
     }
 
-    pub async fn send_msg(&self, msg: &[IoSlice<'_>]) -> io::Result<()> {
+    // send a buffer registered in the Context of this Conn without copying it,
+    // the buffer can be reused once this returns.
+    pub async fn send_registered(&self, buf: LocalBuf) -> Result<()> {
+        self.send.send_registered(buf).await
+    }
+
+    // the space of the message is released when the RecvGuard drops
+    // return Error::Closed once the remote side has closed the Conn
+    pub async fn recv_msg(&self) -> Result<RecvGuard<'_>> {
+        self.recv.recv_msg().await
+    }
+
+    // bring the connection back after its QP has failed: drain and reset the QP,
+    // exchange fresh PSNs over the bootstrap channel and agree with the remote side on
+    // the positions of both ring buffers. the remote side must call recover too,
+    // it notices the failure once its next write or the peer's flush fails.
+    // the messages that never reached the remote side are dropped, their number
+    // is returned.
+    pub async fn recover(&self) -> Result<u64> {
+        let link = &self.send.link;
+        if link.limits.features & features::RECOVERY == 0 {
+            return Err(Error::Recovery(
+                "the remote side doesn't support recovery".to_owned(),
+            ));
+        }
+        // no sending until both sides agree on the buffers
+        let _lock = self.send.lock.lock().await;
+        let qp = &link.qp;
+        qp.drain(link.reactor.config().timeout).await?;
+        qp.reset()?;
+        qp.init()?;
+        for _ in 0..link.limits.rqe_count {
+            qp.post_null_recv()?;
+        }
+        link.state.reset();
+        link.reactor.register(
+            qp.qpn(),
+            Dispatcher::new(qp.clone(), link.tx.clone(), link.state.clone()),
+        );
+
+        // the pending release lengths are covered by the released position
+        while self.send.release_rx().try_recv().is_ok() {}
+        // the packets of the failed connection are out of sequence with a new PSN
+        qp.renew_psn();
+        let local = Resync {
+            endpoint: qp.endpoint(),
+            delivered: link.state.delivered(),
+            released: self.recv.recv_buf.released_position(),
+        };
+        write_frame(qp, Kind::Resync, &local).await?;
+        let remote: Resync = read_frame(qp, Kind::Resync).await?;
+        remote.endpoint.validate()?;
+
+        let dropped = link.allocator.rewind(remote.delivered)?;
+        link.allocator.reset_done(remote.released);
+        // the RQ of the remote side is full again
+        link.sending.store(0, Ordering::Release);
+        link.credit.notify_waiters();
+        qp.reconnect(remote.endpoint)?;
+        Ok(dropped)
+    }
+
+    // close the Conn gracefully: new sends fail, the remote side is told once the
+    // writes in flight have completed, then its close is awaited. the remote side
+    // learns about the close from recv_msg and must call close too, the messages it
+    // sends in between are dropped. the QP is flushed before the buffers are
+    // deregistered, and they are before the QP is destroyed.
+    pub async fn close(self) -> Result<()> {
+        let link = self.send.link.clone();
+        link.state.close();
+        let res = match link.state.error() {
+            // the remote side can't be told, only tear down
+            Some(err) => Err(err),
+            None => {
+                within(
+                    link.reactor.config().timeout,
+                    "close",
+                    self.close_handshake(),
+                )
+                .await
+            }
+        };
+        // nothing is written into the buffers once the QP is flushed
+        let drained = link.qp.drain(link.reactor.config().timeout).await;
+        // the halves park their buffers, the last reference retires the QP
+        drop(self);
+        drop(link);
+        res.and(drained)
+    }
+
+    async fn close_handshake(&self) -> Result<()> {
+        let link = &self.send.link;
+        let closed = Completion::new();
+        {
+            // the sends holding the lock are posted before the notification
+            let _lock = self.send.lock.lock().await;
+            let wr_id = closed.wr_id();
+            if let Err(e) = link.qp.notify(CLOSE_IMM, wr_id) {
+                unsafe { drop(Completion::from_wr_id(wr_id)) };
+                return Err(e);
+            }
+        }
+        // the completions come in order, so the writes before it have completed too
+        closed.wait().await;
+        link.state.check_failure()?;
+        // drop the messages until the close of the remote side
+        let mut queue = self.recv.recv_buf.queue().await;
+        while !link.state.peer_closed() {
+            let (length, imm) = tokio::select! {
+                biased;
+                res = queue.recv() => res?,
+                err = link.state.failed() => return Err(err),
+            };
+            if is_close(length, imm) {
+                link.state.set_peer_closed();
+            }
+        }
+        Ok(())
+    }
+
+    // the latest released position to tell the remote side, 0 for none.
+    pub fn get_release_length(&self) -> Result<u32> {
+        self.send.get_release_length()
+    }
+}
+
+impl SendHalf {
+    // put the halves of the same Conn back together
+    pub fn reunite(self, recv: RecvHalf) -> std::result::Result<Conn, ReuniteError> {
+        if !Arc::ptr_eq(&self.link, &recv.link) {
+            return Err(ReuniteError(self, recv));
+        }
+        Ok(Conn { send: self, recv })
+    }
+
+    pub fn limits(&self) -> &Negotiated {
+        &self.link.limits
+    }
+
+    pub fn context(&self) -> &Arc<Context> {
+        &self.link.qp.ctx
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.link.state.error()
+    }
+
+    // cancel safe: a send dropped before its write is posted leaves nothing behind
+    pub async fn send_msg(&self, msg: &[IoSlice<'_>]) -> Result<()> {
+        self.link.state.check()?;
         // get the total length of the IoSlice of msg
         let total_len = msg.iter().map(|slice| slice.len()).sum::<usize>();
+        self.check_size(total_len)?;
         // allocate the local buffer once.
-        let (local_buf, wr_id) = self.send_buf.alloc(total_len as u32).await;
+        let (local_buf, wr_id) = self
+            .unless_failed(self.send_buf.alloc(total_len as u32))
+            .await??;
+        let wr_id = Unposted::new(&self.link, wr_id);
         // iterate over the slices and copy the data to the local buffer, and send the buffer to the remote
         let mut addr_idx = local_buf.addr;
         msg.iter().for_each(|slice| {
@@ -98,52 +361,279 @@ impl Conn {
             };
             addr_idx += slice.len() as u64;
         });
-        {
-            let _lock = self.lock.lock().await;
-            // too much sending will cause device error(memory exhausted or something)
-            while self.sending.load(Ordering::Acquire) >= MAX_SENDING {
-                tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
-            }
-            self.sending.fetch_add(1, Ordering::AcqRel);
-            let release_length = self.get_release_length();
+        self.post(local_buf, wr_id).await
+    }
 
-            // allocate a remote buffer
-            let buf = self.allocator.alloc(total_len as u32).await;
-            // post a send operation
-            self.qp
-                .write_with_imm(local_buf, buf, release_length, wr_id);
+    // send a buffer registered in the Context of this Conn without copying it,
+    // the buffer can be reused once this returns, or once the Conn has failed if
+    // the send is cancelled.
+    pub async fn send_registered(&self, buf: LocalBuf) -> Result<()> {
+        self.link.state.check()?;
+        self.check_size(buf.length as usize)?;
+        // the daemon completes it when the write completes or is flushed
+        let using = Completion::new();
+        self.post(buf, Unposted::new(&self.link, using.wr_id()))
+            .await?;
+        using.wait().await;
+        self.link.state.check_failure()
+    }
+
+    fn check_size(&self, size: usize) -> Result<()> {
+        if size > self.link.limits.max_msg_size as usize {
+            return Err(Error::MessageTooLarge {
+                size,
+                max: self.link.limits.max_msg_size,
+            });
         }
         Ok(())
     }
 
-    // after calling recv_msg(), need to call release() before calling recv_msg again
-    pub async fn recv_msg(&self) -> io::Result<&[u8]> {
-        let (length, imm) = self.recv_buf.recv().await;
-        self.sending.fetch_add(-1, Ordering::AcqRel);
-        if imm != 0 {
-            self.allocator.update(imm);
+    // wait for fut unless the connection fails first, fut must be cancel safe
+    async fn unless_failed<T>(&self, fut: impl Future<Output = T>) -> Result<T> {
+        tokio::select! {
+            res = fut => Ok(res),
+            err = self.link.state.failed() => Err(err),
         }
-        self.recv_buf.read(length)
     }
 
-    pub async fn release(&self, buf: &[u8]) {
-        let length = buf.len() as u32;
-        if let Some(release_len) = self.recv_buf.notify_release(length) {
-            self.release.0.send(release_len).await.unwrap();
+    async fn post(&self, local_buf: LocalBuf, mut wr_id: Unposted<'_>) -> Result<()> {
+        let link = &self.link;
+        let total_len = local_buf.length;
+        let _lock = self.lock.lock().await;
+        // close may have posted its notification while this send waited for the lock
+        if link.state.is_closing() {
+            // the write never happens, wr_id hands the Completion back as if it completed
+            return Err(Error::Closed);
+        }
+        // too much sending will cause device error(memory exhausted or something)
+        // a received message frees a RQE of the remote side, see RecvHalf::recv_msg
+        self.unless_failed(wait_until(&link.credit, || {
+            link.sending.load(Ordering::Acquire) < link.limits.max_sending as i32
+        }))
+        .await?;
+        wr_id.take_credit();
+
+        // allocate a remote buffer
+        let buf = self.unless_failed(link.allocator.alloc(total_len)).await?;
+        let release_length = self.get_release_length()?;
+        // post a send operation, if it fails wr_id gives back the Completion and the
+        // credit, and the remote allocation is undone so recover doesn't count it
+        if let Err(e) = link
+            .qp
+            .write_with_imm(local_buf, buf, release_length, wr_id.id())
+        {
+            link.allocator.cancel_last();
+            return Err(e);
         }
+        wr_id.posted();
+        Ok(())
+    }
+
+    fn release_rx(&self) -> StdMutexGuard<'_, UnboundedReceiver<u32>> {
+        self.release.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    pub fn get_release_length(&self) -> u32 {
-        match self.release.1.try_recv() {
-            Ok(imm) => imm,
-            Err(TryRecvError::Empty) => 0,
-            Err(_) => {
-                panic!("get imm error");
+    // the latest released position to tell the remote side, 0 for none. the
+    // positions only move forward, so the older ones are skipped.
+    pub fn get_release_length(&self) -> Result<u32> {
+        let mut rx = self.release_rx();
+        let mut imm = 0;
+        loop {
+            match rx.try_recv() {
+                Ok(latest) => imm = latest,
+                // nothing more is released once the RecvHalf is gone
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return Ok(imm),
             }
         }
     }
 }
 
+impl RecvHalf {
+    // put the halves of the same Conn back together
+    pub fn reunite(self, send: SendHalf) -> std::result::Result<Conn, ReuniteError> {
+        send.reunite(self)
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.link.state.error()
+    }
+
+    // the space of the message is released when the RecvGuard drops
+    // return Error::Closed once the remote side has closed the Conn
+    pub async fn recv_msg(&self) -> Result<RecvGuard<'_>> {
+        let link = &self.link;
+        // concurrent callers take the messages one at a time, in arrival order
+        let mut queue = self.recv_buf.queue().await;
+        // nothing follows the close of the remote side
+        if link.state.peer_closed() {
+            return Err(Error::Closed);
+        }
+        // the messages received before a failure can still be read
+        let (length, imm) = tokio::select! {
+            biased;
+            res = queue.recv() => res?,
+            err = link.state.failed() => return Err(err),
+        };
+        if is_close(length, imm) {
+            link.state.set_peer_closed();
+            return Err(Error::Closed);
+        }
+        link.sending.fetch_add(-1, Ordering::AcqRel);
+        link.credit.notify_waiters();
+        // the released positions only move forward in arrival order
+        if imm != 0 {
+            link.allocator.update(imm);
+        }
+        let (seq, buf) = self.recv_buf.read(length)?;
+        drop(queue);
+        Ok(RecvGuard {
+            half: self,
+            seq,
+            buf,
+        })
+    }
+
+    // called once per message, by its RecvGuard, in any order
+    fn release(&self, seq: u64) {
+        if let Some(imm) = self.recv_buf.release(seq) {
+            // the SendHalf may be gone, then the remote side is never told
+            let _ = self.release.send(imm);
+        }
+    }
+}
+
+// the wr_id of a write that isn't posted yet. dropped before it is posted, e.g. by a
+// cancelled send, it completes the Completion so the release task of send_buf moves
+// on, and gives back the RQE credit taken for it.
+struct Unposted<'a> {
+    link: &'a Link,
+    wr_id: u64,
+    credit: bool,
+}
+
+impl<'a> Unposted<'a> {
+    fn new(link: &'a Link, wr_id: u64) -> Self {
+        Self {
+            link,
+            wr_id,
+            credit: false,
+        }
+    }
+
+    fn id(&self) -> u64 {
+        self.wr_id
+    }
+
+    fn take_credit(&mut self) {
+        self.link.sending.fetch_add(1, Ordering::AcqRel);
+        self.credit = true;
+    }
+
+    // the daemon completes the Completion from now on
+    fn posted(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Unposted<'_> {
+    fn drop(&mut self) {
+        unsafe { Completion::from_wr_id(self.wr_id) }.complete();
+        if self.credit {
+            self.link.sending.fetch_add(-1, Ordering::AcqRel);
+            self.link.credit.notify_waiters();
+        }
+    }
+}
+
+// the halves passed to reunite belong to different Conns
+pub struct ReuniteError(pub SendHalf, pub RecvHalf);
+
+impl fmt::Debug for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the halves are not from the same Conn")
+    }
+}
+
+impl std::error::Error for ReuniteError {}
+
+// a message received by recv_msg, its space in the ring is released to the remote
+// side when the guard drops. the guards may be dropped in any order.
+pub struct RecvGuard<'a> {
+    half: &'a RecvHalf,
+    seq: u64,
+    buf: &'a [u8],
+}
+
+impl RecvGuard<'_> {
+    // copy the message out and release its space
+    pub fn into_owned(self) -> Vec<u8> {
+        self.buf.to_vec()
+    }
+}
+
+impl Deref for RecvGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf
+    }
+}
+
+impl AsRef<[u8]> for RecvGuard<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self.buf
+    }
+}
+
+impl Drop for RecvGuard<'_> {
+    fn drop(&mut self) {
+        self.half.release(self.seq);
+    }
+}
+
+impl Link {
+    fn park(&self, buf: Box<dyn Send>) {
+        self.parked
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(buf);
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // the remote side may still write into recv_buf, the reactor keeps the parked
+        // buffers until the QP is flushed
+        let parked = std::mem::take(self.parked.get_mut().unwrap_or_else(|e| e.into_inner()));
+        self.reactor.retire(self.qp.clone(), parked);
+    }
+}
+
+impl Drop for SendHalf {
+    fn drop(&mut self) {
+        // the writes in flight read from send_buf until the QP is flushed
+        let send_buf = unsafe { ManuallyDrop::take(&mut self.send_buf) };
+        self.link.park(Box::new(send_buf));
+    }
+}
+
+impl Drop for RecvHalf {
+    fn drop(&mut self) {
+        let recv_buf = unsafe { ManuallyDrop::take(&mut self.recv_buf) };
+        self.link.park(Box::new(recv_buf));
+    }
+}
+
+fn is_close(length: u32, imm: u32) -> bool {
+    length == 0 && imm == CLOSE_IMM
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnType {
     Client,
@@ -152,47 +642,107 @@ pub enum ConnType {
 
 // client side use this function to connect to server
 pub async fn connect(addr: &str) -> Result<Conn> {
-    // connect to server
-    let stream = TcpStream::connect(addr).await?;
+    connect_with(addr, &ConnConfig::default()).await
+}
 
-    let device = Arc::new(Device::new(default_device()));
-    // Create a new QP
-    let mut qp = QP::new(device, QPCap::new(MAX_QP_WR, MAX_QP_WR, 5, 5));
-    if let Err(err) = qp.init() {
-        error!("err: {}", err);
+// the Conn is sized by config, both sides settle on the smaller limits
+pub async fn connect_with(addr: &str, config: &ConnConfig) -> Result<Conn> {
+    if config.rdma_cm {
+        return cm::connect(addr, config).await;
     }
-    qp.set_stream(stream);
-    qp.handshake().await;
-    // exchange recv_buf with client
-    let (recv_buf, remote_mr, rx) = qp.exchange_recv_buf().await;
-    let conn = Conn::new(Arc::new(qp), recv_buf, remote_mr, rx).await;
+    // connect opens its own device, so the reactor serves only this Conn
+    let reactor = Reactor::new(Context::open(&config.device)?, config)?;
+    connect_to(&reactor, addr).await
+}
 
-    Ok(conn)
+// connect on a reactor shared with other Conns, they share the PD and CQs of the device.
+// the Conn is built with the config of the reactor.
+pub async fn connect_to(reactor: &Arc<Reactor>, addr: &str) -> Result<Conn> {
+    if reactor.config().rdma_cm {
+        return cm::connect_to(reactor, addr).await;
+    }
+    within(reactor.config().timeout, SETUP, async {
+        // connect to server
+        let stream = TcpStream::connect(addr).await?;
+        setup(reactor.clone(), Box::new(TcpBootstrap::new(stream))).await
+    })
+    .await
+}
+
+// set up a Conn with the remote side at the other end of bootstrap, e.g. a launcher
+// that hands both processes a channel without opening a tcp port. both sides call this.
+pub async fn connect_over(reactor: &Arc<Reactor>, bootstrap: Box<dyn Bootstrap>) -> Result<Conn> {
+    within(
+        reactor.config().timeout,
+        SETUP,
+        setup(reactor.clone(), bootstrap),
+    )
+    .await
+}
+
+// server side accept a connection on the stream
+async fn accept(reactor: Arc<Reactor>, stream: TcpStream) -> Result<Conn> {
+    connect_over(&reactor, Box::new(TcpBootstrap::new(stream))).await
+}
+
+// both sides set up the Conn the same way
+async fn setup(reactor: Arc<Reactor>, bootstrap: Box<dyn Bootstrap>) -> Result<Conn> {
+    let config = reactor.config();
+    // Create a new QP
+    let mut qp = reactor.create_qp()?;
+    qp.init()?;
+    qp.set_bootstrap(bootstrap);
+    qp.handshake(Hello::new(qp.endpoint())).await?;
+    // exchange recv_buf with the remote side
+    let (mut recv_buf, remote_mr, tx, limits) = qp.exchange_recv_buf(config.limits()).await?;
+    recv_buf.set_release_threshold(config.release_threshold);
+    Conn::new(Arc::new(qp), recv_buf, remote_mr, tx, reactor, limits).await
+}
+
+pub(super) const SETUP: &str = "connection setup";
+
+// fail with TimedOut naming op if fut doesn't finish in time
+pub(super) async fn within<T>(
+    timeout: Option<Duration>,
+    op: &'static str,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.map_err(|_| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timed out", op),
+            ))
+        })?,
+        None => fut.await,
+    }
 }
 
 // server side use this function to listen to client
-pub async fn run(addr: String, sender: Sender<Conn>) {
-    let listener = TcpListener::bind(addr.clone()).await.unwrap();
-    let device = Arc::new(Device::new(default_device()));
+pub async fn run(listener: TcpListener, reactor: Arc<Reactor>, sender: Sender<Conn>) {
     loop {
         match listener.accept().await {
             Ok((stream, addr)) => {
                 info!("New connection from {}", addr);
-                // Create a QP for the new connection
-                let mut qp = QP::new(device.clone(), QPCap::new(16384, 16384, 5, 5));
-                if let Err(err) = qp.init() {
-                    error!("err: {}", err);
-                }
-                qp.set_stream(stream);
-                qp.handshake().await;
-                // exchange recv_buf with client
-                let (recv_buf, remote_mr, tx) = qp.exchange_recv_buf().await;
-                let conn = Conn::new(Arc::new(qp), recv_buf, remote_mr, tx).await;
-
-                if let Err(e) = sender.send(conn).await {
-                    error!("server send conn error: {}", e);
+                if sender.is_closed() {
                     break;
                 }
+                // a slow or silent peer only holds up its own handshake, and a
+                // failed one only drops this connection
+                let reactor = reactor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let conn = match accept(reactor, stream).await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            error!("server accept {} error: {}", addr, e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = sender.send(conn).await {
+                        error!("server send conn error: {}", e);
+                    }
+                });
             }
             Err(e) => {
                 error!("server listener accepte error: {}", e);
@@ -201,37 +751,3 @@ pub async fn run(addr: String, sender: Sender<Conn>) {
         }
     }
 }
-
-pub struct MyReceiver<T>(*mut Receiver<T>);
-
-unsafe impl<T> Send for MyReceiver<T> {}
-unsafe impl<T> Sync for MyReceiver<T> {}
-
-impl<T> MyReceiver<T> {
-    pub fn new(rx: Receiver<T>) -> Self {
-        MyReceiver(Box::into_raw(Box::new(rx)))
-    }
-
-    pub fn try_recv(&self) -> core::result::Result<T, TryRecvError> {
-        let rx = unsafe { &mut *self.0 };
-        rx.try_recv()
-    }
-
-    pub fn get_receiver(&self) -> &mut Receiver<T> {
-        let rx = unsafe { &mut *self.0 };
-        rx
-    }
-
-    pub async fn recv(&self) -> T {
-        let rx = self.get_receiver();
-        rx.recv().await.unwrap()
-    }
-}
-
-impl<T> Drop for MyReceiver<T> {
-    fn drop(&mut self) {
-        unsafe {
-            let _ = Box::from_raw(self.0);
-        }
-    }
-}
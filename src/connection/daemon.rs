@@ -1,79 +1,283 @@
 use log::error;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::UnboundedSender;
 
+use super::reactor::Shared;
+use super::state::ConnState;
 use crate::types::{
-    cq::Opcode::{Write, WriteWithImm},
-    mr::RecvBuffer,
+    completion::Completion,
+    cq::{
+        Opcode::{Write, WriteWithImm},
+        WCStatus, WC,
+    },
     qp::QP,
 };
-use std::sync::{atomic::AtomicBool, Arc};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
-// if use tokio run a task of polling, the task will be blocked by the tokio runtime.
-pub async fn polling(qp: Arc<QP>, tx: Sender<(u32, u32)>) {
-    loop {
-        let wcs = match qp.cq.poll_wc(100) {
-            Ok(wcs) => wcs,
-            Err(_) => {
-                error!("poll wc error");
-                break;
+// how the reactor waits for work completions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollingStrategy {
+    // poll the CQ continuously, only yield to the runtime between empty polls
+    BusyPoll,
+    // spin, then yield, then park as the CQ stays empty
+    Adaptive(AdaptivePolling),
+    // sleep until the completion channel of the CQ signals, then drain the CQ
+    Event,
+}
+
+impl Default for PollingStrategy {
+    fn default() -> Self {
+        PollingStrategy::Adaptive(AdaptivePolling::default())
+    }
+}
+
+impl PollingStrategy {
+    // whether the CQ need to be created with a completion channel
+    pub fn with_channel(&self) -> bool {
+        match self {
+            PollingStrategy::BusyPoll => false,
+            PollingStrategy::Adaptive(adaptive) => adaptive.park == Park::Event,
+            PollingStrategy::Event => true,
+        }
+    }
+
+    // the phase the daemon is in after the CQ has been empty for `idle`
+    pub(crate) fn phase(&self, idle: Duration) -> Phase {
+        match self {
+            PollingStrategy::BusyPoll => Phase::Yield,
+            PollingStrategy::Adaptive(adaptive) => {
+                if idle < adaptive.spin {
+                    Phase::Spin
+                } else if idle < adaptive.spin + adaptive.yield_for {
+                    Phase::Yield
+                } else {
+                    Phase::Park(adaptive.park)
+                }
             }
-        };
-        for wc in wcs.iter() {
-            // dipatch the wc
+            PollingStrategy::Event => Phase::Park(Park::Event),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptivePolling {
+    // busy-poll without yielding for this long after the last completion
+    pub spin: Duration,
+    // then poll and yield to the runtime for this long
+    pub yield_for: Duration,
+    // then wait like this between polls
+    pub park: Park,
+}
+
+impl Default for AdaptivePolling {
+    fn default() -> Self {
+        Self {
+            spin: Duration::from_micros(50),
+            yield_for: Duration::from_millis(1),
+            park: Park::Sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Park {
+    // sleep for a while, then poll again
+    Sleep(Duration),
+    // wait for the completion channel, the CQ must be created with it
+    Event,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    Spin,
+    Yield,
+    Park(Park),
+}
+
+// time the daemon spent in each phase, in nanoseconds
+#[derive(Debug, Default)]
+pub struct PollingStats {
+    pub(crate) dispatch: AtomicU64,
+    pub(crate) spin: AtomicU64,
+    pub(crate) yielding: AtomicU64,
+    pub(crate) park: AtomicU64,
+    completions: AtomicU64,
+}
+
+impl PollingStats {
+    pub(crate) fn add(&self, counter: &AtomicU64, elapsed: Duration) {
+        counter.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_completions(&self, n: usize) {
+        self.completions.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> PollingReport {
+        let load = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed));
+        PollingReport {
+            dispatch: load(&self.dispatch),
+            spin: load(&self.spin),
+            yielding: load(&self.yielding),
+            park: load(&self.park),
+            completions: self.completions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollingReport {
+    // polling and handling non-empty batches of wcs
+    pub dispatch: Duration,
+    // busy-polling an empty CQ without yielding
+    pub spin: Duration,
+    // polling an empty CQ and yielding to the runtime
+    pub yielding: Duration,
+    // sleeping or waiting for the completion channel
+    pub park: Duration,
+    pub completions: u64,
+}
+
+// routes the wcs of one QP to its Conn
+pub struct Dispatcher {
+    qp: Arc<QP>,
+    // to the RecvBuffer of the Conn, unbounded so that dispatching never blocks the poller
+    tx: UnboundedSender<(u32, u32)>,
+    state: Arc<ConnState>,
+}
+
+impl Dispatcher {
+    pub fn new(qp: Arc<QP>, tx: UnboundedSender<(u32, u32)>, state: Arc<ConnState>) -> Self {
+        Self { qp, tx, state }
+    }
+
+    pub(crate) fn qp(&self) -> &Arc<QP> {
+        &self.qp
+    }
+
+    // the Conn has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
 
-            // todo: check the wc status
+    // dispatch the wcs, return false if the Conn has failed or been dropped and
+    // the CQ should not be polled any more.
+    pub fn dispatch(&self, wcs: &[WC]) -> bool {
+        for wc in wcs.iter() {
+            // the opcode of a failed wc is undefined, only wr_id and status are valid.
+            if wc.status() != WCStatus::Success {
+                self.qp.set_error();
+                fail(&self.state, wc);
+                // stop re-posting receives, dropping tx wakes the pending recv_msg.
+                return false;
+            }
 
             // match opcode
             match wc.opcode() {
                 WriteWithImm => {
                     // post recv request immediately to avoid RQE shortage
-                    qp.post_null_recv();
+                    if let Err(e) = self.qp.post_null_recv() {
+                        error!("{}", e);
+                        return false;
+                    }
                     let length = wc.byte_len();
                     let imm = wc.imm_data();
-                    // there is no need to spawn a task.
-                    tx.send((length, imm)).await.unwrap();
+                    // wakes the pending recv_msg
+                    if self.tx.send((length, imm)).is_err() {
+                        // the Conn has been dropped
+                        return false;
+                    }
+                    self.state.deliver();
                 }
                 Write => {
-                    let using_p = wc.wr_id() as *const AtomicBool;
-                    unsafe {
-                        let using = Arc::from_raw(using_p);
-                        using.store(false, std::sync::atomic::Ordering::Relaxed);
-                    }
+                    // wakes the tasks waiting for the write
+                    unsafe { Completion::from_wr_id(wc.wr_id()) }.complete();
                 }
                 _ => {
                     // todo: handle other opcode
                 }
             }
         }
-        if wcs.len() == 0 {
-            // the interval of polling mattes a little with the throughput.
-            // too long interval will affect latency.
-            // too short interval will cause high cpu usage and other tasks can't be executed.
-            // influence the situation of instantaneous mass requests that may cause RQE shortage.
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-        }
+        true
     }
 }
 
-// cann't work in soft-RoCE
-pub fn notify(qp: Arc<QP>, recv_buf: RecvBuffer) {
+// if use tokio run a task of polling, the task will be blocked by the tokio runtime.
+// use a polling thread instead if it matters.
+pub(crate) async fn polling(strategy: PollingStrategy, shared: Arc<Shared>, shard: usize) {
+    let stats = shared.stats();
+    let cq = shared.cq(shard);
+    let mut last_completion = Instant::now();
+    // whether the CQ is empty and armed
+    let mut armed = false;
     loop {
-        println!("req_notify");
-        if let Err(e) = qp.cq.req_notify(true) {
-            println!("req_notify error: {:?}", e);
-            break;
+        let start = Instant::now();
+        let polled = match shared.poll(shard) {
+            Ok(polled) => polled,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        };
+        if polled > 0 {
+            last_completion = Instant::now();
+            // keep draining, the CQ is re-armed once it's empty
+            armed = false;
+            stats.add(&stats.dispatch, start.elapsed());
+            continue;
         }
-        println!("wait for wc");
-        qp.cq.get_event();
-        println!("ack_event");
-        qp.cq.ack_event(1);
-        // polling
-        let wcs = qp.cq.poll_wc(10).unwrap();
-        for wc in wcs.iter() {
-            let length = wc.byte_len();
-            let data = recv_buf.read(length).unwrap();
-            // handel data
-            println!("recv data: {:?}", data);
+        match strategy.phase(last_completion.elapsed()) {
+            Phase::Spin => {
+                std::hint::spin_loop();
+                stats.add(&stats.spin, start.elapsed());
+            }
+            Phase::Yield => {
+                tokio::task::yield_now().await;
+                stats.add(&stats.yielding, start.elapsed());
+            }
+            Phase::Park(Park::Sleep(interval)) => {
+                // too long interval will affect latency, too short interval will cause high cpu usage.
+                tokio::time::sleep(interval).await;
+                stats.add(&stats.park, start.elapsed());
+            }
+            Phase::Park(Park::Event) => {
+                if !armed {
+                    // completions arrived between the last poll and arming don't generate an event,
+                    // so poll once more after arming before going to sleep.
+                    if let Err(e) = cq.req_notify(false) {
+                        error!("{}", e);
+                        break;
+                    }
+                    armed = true;
+                    continue;
+                }
+                if let Err(e) = cq.wait_event().await {
+                    error!("{}", e);
+                    break;
+                }
+                armed = false;
+                stats.add(&stats.park, start.elapsed());
+            }
         }
     }
 }
+
+// record the first error of the connection and reclaim the wr_id of the failed wc.
+fn fail(state: &ConnState, wc: &WC) {
+    // a closing Conn flushes its QP on purpose
+    if state.fail(wc.status()) && !state.is_closing() {
+        error!("qp work completion error: {:?}", wc);
+    }
+    reclaim(wc);
+}
+
+// complete the Completion carried by the wr_id of a send wc that won't be dispatched.
+pub(crate) fn reclaim(wc: &WC) {
+    // recv requests are posted with wr_id 0, send requests carry a Completion.
+    if wc.wr_id() != 0 {
+        unsafe { Completion::from_wr_id(wc.wr_id()) }.complete();
+    }
+}
@@ -1,4 +1,9 @@
 pub mod client;
+pub mod cm;
+pub mod config;
 pub mod conn;
 pub mod daemon;
+pub mod poller;
+pub mod reactor;
 pub mod server;
+pub mod state;
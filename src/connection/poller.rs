@@ -0,0 +1,186 @@
+//! a dedicated OS thread polling the shared CQs of a device, so that
+//! RDMA completions are not delayed by the work on the tokio runtime.
+
+use super::daemon::{Park, Phase, PollingStrategy};
+use super::reactor::Shared;
+use crate::error::{Error, Result};
+use log::error;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::{io, time::Instant};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PollingThread {
+    // pin the polling thread to this cpu
+    pub cpu: Option<usize>,
+}
+
+impl PollingThread {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pin_to(mut self, cpu: usize) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
+    // the cpu must be online, CPU_SET panics beyond CPU_SETSIZE
+    pub fn validate(&self) -> Result<()> {
+        match self.cpu {
+            Some(cpu) => check_cpu(cpu, unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_cpu(cpu: usize, online: libc::c_long) -> Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize || (online > 0 && cpu >= online as usize) {
+        return Err(Error::InvalidConfig(format!(
+            "can't pin the polling thread to cpu {}, {} cpus are online",
+            cpu, online
+        )));
+    }
+    Ok(())
+}
+
+pub struct Poller {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Poller {
+    // the completion channel can't be waited on the thread, so strategy must not park on events.
+    pub(crate) fn new(
+        strategy: PollingStrategy,
+        config: &PollingThread,
+        shared: Arc<Shared>,
+    ) -> Result<Self> {
+        if strategy.with_channel() {
+            return Err(Error::InvalidConfig(
+                "the polling thread can't wait on completion channels".to_owned(),
+            ));
+        }
+        config.validate()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_c = stop.clone();
+        let cpu = config.cpu;
+        let (started_tx, started_rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("ibv-poller".to_owned())
+            .spawn(move || {
+                let started = match cpu {
+                    Some(cpu) => pin(cpu),
+                    None => Ok(()),
+                };
+                let ok = started.is_ok();
+                let _ = started_tx.send(started);
+                if ok {
+                    poll_loop(strategy, &shared, &stop_c);
+                }
+            })?;
+        // report the pinning error to the caller
+        match started_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(Error::Io(e)),
+            Err(_) => return Err(Error::Disconnected),
+        }
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn poll_loop(strategy: PollingStrategy, shared: &Shared, stop: &AtomicBool) {
+    let stats = shared.stats();
+    let mut last_completion = Instant::now();
+    while !stop.load(Ordering::Acquire) {
+        let start = Instant::now();
+        let mut polled = 0;
+        for shard in 0..shared.shards() {
+            match shared.poll(shard) {
+                Ok(n) => polled += n,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        if polled > 0 {
+            last_completion = Instant::now();
+            stats.add(&stats.dispatch, start.elapsed());
+            continue;
+        }
+        match strategy.phase(last_completion.elapsed()) {
+            Phase::Spin => {
+                std::hint::spin_loop();
+                stats.add(&stats.spin, start.elapsed());
+            }
+            Phase::Yield => {
+                thread::yield_now();
+                stats.add(&stats.yielding, start.elapsed());
+            }
+            Phase::Park(Park::Sleep(interval)) => {
+                thread::sleep(interval);
+                stats.add(&stats.park, start.elapsed());
+            }
+            Phase::Park(Park::Event) => unreachable!("rejected by Poller::new"),
+        }
+    }
+}
+
+// pin the current thread to cpu
+fn pin(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_to_an_online_cpu() {
+        assert!(check_cpu(0, 1).is_ok());
+        assert!(check_cpu(7, 8).is_ok());
+        assert!(matches!(check_cpu(8, 8), Err(Error::InvalidConfig(_))));
+        // sysconf failed, only CPU_SETSIZE is known
+        assert!(check_cpu(8, -1).is_ok());
+        let setsize = libc::CPU_SETSIZE as usize;
+        assert!(matches!(
+            check_cpu(setsize, -1),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            check_cpu(setsize, setsize as libc::c_long * 2),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn no_cpu_is_valid() {
+        assert!(PollingThread::new().validate().is_ok());
+        assert!(PollingThread::new().pin_to(0).validate().is_ok());
+        assert!(PollingThread::new().pin_to(usize::MAX).validate().is_err());
+    }
+}
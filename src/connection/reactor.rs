@@ -0,0 +1,335 @@
+//! per-device reactor: the QPs of every Conn on a device share one (or N sharded) CQs,
+//! and the wcs are dispatched to the Conns by qp_num, so the polling cost doesn't grow
+//! with the number of connections.
+
+use super::config::ConnConfig;
+use super::daemon::{polling, reclaim, Dispatcher, PollingReport, PollingStats};
+use super::poller::Poller;
+use crate::error::Result;
+use crate::types::{
+    completion::Completion,
+    context::Context,
+    cq::{register_fd, CQ},
+    qp::{QPBuilder, QP},
+};
+use log::{error, warn};
+use rdma_sys::{ibv_ack_async_event, ibv_async_event, ibv_event_type, ibv_get_async_event};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+// how long a dropped reactor waits for the flushed wcs of the retired QPs
+const RETIRE_TIMEOUT: Duration = Duration::from_millis(100);
+const RECLAIM_INTERVAL: Duration = Duration::from_millis(1);
+
+pub struct Reactor {
+    ctx: Arc<Context>,
+    // the config of the Conns on this reactor
+    config: ConnConfig,
+    shared: Arc<Shared>,
+    // round-robin over the shards for new QPs
+    next: AtomicUsize,
+    // the shards are polled either by a task per shard or by one polling thread
+    tasks: Vec<JoinHandle<()>>,
+    _poller: Option<Poller>,
+    // reads the async events of the device, None if another reactor on the
+    // Context does
+    events: Option<JoinHandle<()>>,
+}
+
+pub(crate) struct Shared {
+    // the CQ size bounds the outstanding completions of all the QPs in a shard, a CQ
+    // grows as QPs are created on it and rejects them beyond max_cqe, see CQ::reserve
+    cqs: Vec<Arc<CQ>>,
+    routes: RwLock<HashMap<u32, Dispatcher>>,
+    // the QPs of dropped Conns, kept until their flushed wcs have been reclaimed
+    retired: Mutex<Vec<Retired>>,
+    stats: PollingStats,
+}
+
+// dropped in field order: once the QP is destroyed no more DMA touches the buffers,
+// even if its markers have never been flushed
+struct Retired {
+    _qp: Arc<QP>,
+    // the MRs and memory of the dropped Conn
+    _buffers: Vec<Box<dyn Send>>,
+    markers: Vec<Arc<Completion>>,
+}
+
+impl Retired {
+    fn flushed(&self) -> bool {
+        self.markers.iter().all(|flushed| !flushed.is_pending())
+    }
+}
+
+impl Reactor {
+    // must be called within a tokio runtime
+    pub fn new(ctx: Arc<Context>, config: &ConnConfig) -> Result<Arc<Self>> {
+        config.validate()?;
+        let with_channel = config.with_channel();
+        let cqs = (0..config.cq_shards)
+            .map(|_| CQ::new(ctx.device().clone(), with_channel).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let shared = Arc::new(Shared {
+            cqs,
+            routes: RwLock::new(HashMap::new()),
+            retired: Mutex::new(Vec::new()),
+            stats: PollingStats::default(),
+        });
+        let (tasks, poller) = match &config.polling_thread {
+            Some(polling_thread) => (
+                Vec::new(),
+                Some(Poller::new(config.polling, polling_thread, shared.clone())?),
+            ),
+            None => (
+                (0..config.cq_shards)
+                    .map(|shard| tokio::spawn(polling(config.polling, shared.clone(), shard)))
+                    .collect(),
+                None,
+            ),
+        };
+        let events = ctx
+            .claim_async_events()
+            .then(|| tokio::spawn(async_events(ctx.clone(), shared.clone())));
+        Ok(Arc::new(Self {
+            ctx,
+            config: config.clone(),
+            shared,
+            next: AtomicUsize::new(0),
+            tasks,
+            _poller: poller,
+            events,
+        }))
+    }
+
+    pub fn context(&self) -> &Arc<Context> {
+        &self.ctx
+    }
+
+    pub fn config(&self) -> &ConnConfig {
+        &self.config
+    }
+
+    // create a QP on one of the shared CQs
+    pub fn create_qp(&self) -> Result<QP> {
+        let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.shared.cqs.len();
+        let mut qp = self.config.qp.clone();
+        qp.cap = qp.cap.max_inline_data(self.config.inline_threshold);
+        QPBuilder::new(self.ctx.clone(), self.shared.cqs[shard].clone())
+            .config(qp)
+            .build()
+    }
+
+    // the wcs of the QP will be dispatched to the Conn until it fails or is dropped
+    pub fn register(&self, qpn: u32, dispatcher: Dispatcher) {
+        write(&self.shared.routes).insert(qpn, dispatcher);
+    }
+
+    // the Conn of the QP is dropped: stop dispatching to it and flush the QP. the
+    // QP is kept until the flushed wcs are polled, or the wr_ids they carry leak, and
+    // the buffers of the Conn until then, the remote side may still write into them.
+    pub fn retire(&self, qp: Arc<QP>, buffers: Vec<Box<dyn Send>>) {
+        write(&self.shared.routes).remove(&qp.qpn());
+        match qp.flush() {
+            Ok(markers) => lock(&self.shared.retired).push(Retired {
+                _qp: qp,
+                _buffers: buffers,
+                markers,
+            }),
+            Err(e) => error!("flush qp {} error: {}", qp.qpn(), e),
+        }
+    }
+
+    // time the reactor has spent in each polling phase
+    pub fn report(&self) -> PollingReport {
+        self.shared.stats.report()
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+        if let Some(events) = &self.events {
+            events.abort();
+            self.ctx.release_async_events();
+        }
+        // reclaim the wr_ids of the retired QPs off the dropping thread, the task
+        // keeps the CQs until it is done
+        if lock(&self.shared.retired).is_empty() {
+            return;
+        }
+        let shared = self.shared.clone();
+        let reclaim = move || shared.reclaim_retired(RETIRE_TIMEOUT);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(reclaim)),
+            Err(_) => drop(std::thread::spawn(reclaim)),
+        }
+    }
+}
+
+impl Shared {
+    pub(crate) fn shards(&self) -> usize {
+        self.cqs.len()
+    }
+
+    pub(crate) fn cq(&self, shard: usize) -> &CQ {
+        &self.cqs[shard]
+    }
+
+    pub(crate) fn stats(&self) -> &PollingStats {
+        &self.stats
+    }
+
+    // poll until the flushed wcs of the retired QPs are reclaimed or timeout passes
+    fn reclaim_retired(&self, timeout: Duration) {
+        let start = Instant::now();
+        while !lock(&self.retired).is_empty() && start.elapsed() < timeout {
+            for shard in 0..self.shards() {
+                if let Err(e) = self.poll(shard) {
+                    error!("{}", e);
+                    return;
+                }
+            }
+            std::thread::sleep(RECLAIM_INTERVAL);
+        }
+    }
+
+    fn on_async_event(&self, event: &ibv_async_event) {
+        use ibv_event_type::*;
+        match event.event_type {
+            IBV_EVENT_QP_FATAL | IBV_EVENT_QP_REQ_ERR | IBV_EVENT_QP_ACCESS_ERR => {
+                let qpn = unsafe { (*event.element.qp).qp_num };
+                // a retired QP is being flushed anyway
+                if let Some(dispatcher) = read(&self.routes).get(&qpn) {
+                    dispatcher.qp().set_error();
+                    warn!("qp {} async event {}", qpn, event.event_type);
+                }
+            }
+            IBV_EVENT_DEVICE_FATAL => {
+                error!("device fatal async event");
+                for dispatcher in read(&self.routes).values() {
+                    dispatcher.qp().set_error();
+                }
+            }
+            kind => warn!("async event {}", kind),
+        }
+    }
+
+    // poll the shard once and dispatch the wcs to their Conns, return the number of wcs.
+    pub(crate) fn poll(&self, shard: usize) -> Result<usize> {
+        let wcs = self.cqs[shard].poll_wc(100)?;
+        if wcs.is_empty() {
+            return Ok(0);
+        }
+        let mut closed = Vec::new();
+        let mut reclaimed = false;
+        {
+            let routes = read(&self.routes);
+            for wc in wcs.iter() {
+                match routes.get(&wc.qp_num()) {
+                    Some(dispatcher) => {
+                        if !dispatcher.dispatch(std::slice::from_ref(wc)) {
+                            closed.push(wc.qp_num());
+                        }
+                    }
+                    // the Conn has failed or been dropped, e.g. a flushed wc
+                    None => {
+                        reclaim(wc);
+                        reclaimed = true;
+                    }
+                }
+            }
+        }
+        if reclaimed {
+            // the QPs whose markers have been flushed can go with their buffers
+            let flushed = {
+                let mut retired = lock(&self.retired);
+                let (flushed, pending) = std::mem::take(&mut *retired)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(Retired::flushed);
+                *retired = pending;
+                flushed
+            };
+            dispose(flushed);
+        }
+        if reclaimed || !closed.is_empty() {
+            let mut routes = write(&self.routes);
+            for qpn in closed {
+                routes.remove(&qpn);
+            }
+            // a wc nobody is routed to follows a Conn going away, take the chance
+            // to forget the dropped ones
+            if reclaimed {
+                routes.retain(|_, dispatcher| !dispatcher.is_closed());
+            }
+        }
+        self.stats.add_completions(wcs.len());
+        Ok(wcs.len())
+    }
+}
+
+// destroying QPs and deregistering MRs block in the kernel, off the polling path when
+// there is a runtime. the polling thread has nothing else to do.
+fn dispose(retired: Vec<Retired>) {
+    if retired.is_empty() {
+        return;
+    }
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => drop(handle.spawn_blocking(move || drop(retired))),
+        Err(_) => drop(retired),
+    }
+}
+
+// the first reactor on a Context reads its async events, only the QPs of that
+// reactor are updated. a QP moved to ERROR by the hardware also flushes its RQEs,
+// which fails the Conn through the daemon, the event only keeps QP::status in step
+// with the hardware. QP::expect asks the driver for the QPs of other reactors.
+async fn async_events(ctx: Arc<Context>, shared: Arc<Shared>) {
+    let fd = match register_fd(unsafe { (*ctx.device().inner()).async_fd }) {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("register async event fd error: {}", e);
+            return;
+        }
+    };
+    loop {
+        let mut guard = match fd.readable().await {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("async event fd error: {}", e);
+                return;
+            }
+        };
+        let mut event = unsafe { std::mem::zeroed::<ibv_async_event>() };
+        if unsafe { ibv_get_async_event(ctx.device().inner(), &mut event) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                error!("get async event error: {}", err);
+                return;
+            }
+            guard.clear_ready();
+            continue;
+        }
+        shared.on_async_event(&event);
+        unsafe { ibv_ack_async_event(&mut event) };
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
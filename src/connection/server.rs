@@ -1,24 +1,64 @@
-use crate::connection::conn::Conn;
+use crate::connection::{config::ConnConfig, conn::Conn};
+use crate::error::{Error, Result};
+use crate::types::context::Context;
+use std::sync::Arc;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{channel, Receiver};
+use tokio::task::JoinHandle;
 
+use super::cm;
 use super::conn::run;
+use super::reactor::Reactor;
 pub struct Server {
     pub addr: String,
     incoming: Receiver<Conn>,
+    reactor: Arc<Reactor>,
+    // accepts the connections, stopped when the Server is dropped
+    task: JoinHandle<()>,
 }
 
-unsafe impl<'a> Send for Server {}
-unsafe impl<'a> Sync for Server {}
-
 impl Server {
-    pub async fn new(addr: String) -> Self {
+    pub async fn new(addr: String) -> Result<Self> {
+        Self::with_config(addr, ConnConfig::default()).await
+    }
+
+    // every accepted Conn is built with config
+    pub async fn with_config(addr: String, config: ConnConfig) -> Result<Self> {
         let (tx, rx) = channel(10);
-        let address = addr.clone();
-        tokio::spawn(run(address, tx));
-        Server { addr, incoming: rx }
+        // all the Conns on the device share the PD and CQs of one reactor
+        let (reactor, task) = if config.rdma_cm {
+            let listener = cm::Listener::bind(&addr).await?;
+            // a listener bound to a rdma address accepts only on its device
+            let reactor =
+                Reactor::new(Context::open(&listener.selector(&config.device))?, &config)?;
+            let task = tokio::spawn(cm::run(listener, reactor.clone(), tx));
+            (reactor, task)
+        } else {
+            let listener = TcpListener::bind(addr.clone()).await?;
+            let reactor = Reactor::new(Context::open(&config.device)?, &config)?;
+            let task = tokio::spawn(run(listener, reactor.clone(), tx));
+            (reactor, task)
+        };
+        Ok(Server {
+            addr,
+            incoming: rx,
+            reactor,
+            task,
+        })
+    }
+
+    // buffers registered in this Context can be sent on every accepted Conn
+    pub fn context(&self) -> &Arc<Context> {
+        self.reactor.context()
+    }
+
+    pub async fn accept(&mut self) -> Result<Conn> {
+        self.incoming.recv().await.ok_or(Error::Disconnected)
     }
+}
 
-    pub async fn accept(&mut self) -> Conn {
-        self.incoming.recv().await.unwrap()
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
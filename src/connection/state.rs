@@ -0,0 +1,115 @@
+//! state shared by a `Conn` and its polling daemon.
+
+use crate::error::{Error, Result};
+use crate::types::cq::WCStatus;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Mutex,
+};
+use tokio::sync::Notify;
+
+pub struct ConnState {
+    // the first failed work completion, the connection is unusable once it is set
+    failure: Mutex<Option<WCStatus>>,
+    notify: Notify,
+    // number of messages received from the remote side
+    delivered: AtomicU64,
+    // close has been called, no new sends
+    closing: AtomicBool,
+    // the close of the remote side has arrived, no more messages follow it
+    peer_closed: AtomicBool,
+}
+
+impl ConnState {
+    pub fn new() -> Self {
+        Self {
+            failure: Mutex::new(None),
+            notify: Notify::new(),
+            delivered: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+            peer_closed: AtomicBool::new(false),
+        }
+    }
+
+    // move the connection to failed state, only the first error is recorded.
+    // return true if this call is the one that failed the connection.
+    pub fn fail(&self, status: WCStatus) -> bool {
+        let mut failure = self.failure.lock().unwrap_or_else(|e| e.into_inner());
+        if failure.is_some() {
+            return false;
+        }
+        *failure = Some(status);
+        drop(failure);
+        // wake every pending send_msg/recv_msg
+        self.notify.notify_waiters();
+        true
+    }
+
+    // the connection has been recovered, forget the failure
+    pub fn reset(&self) {
+        *self.failure.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    pub fn deliver(&self) {
+        self.delivered.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Acquire)
+    }
+
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Release);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Acquire)
+    }
+
+    pub fn set_peer_closed(&self) {
+        self.peer_closed.store(true, Ordering::Release);
+    }
+
+    pub fn peer_closed(&self) -> bool {
+        self.peer_closed.load(Ordering::Acquire)
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        let failure = self.failure.lock().unwrap_or_else(|e| e.into_inner());
+        failure.map(Error::WorkCompletion)
+    }
+
+    pub fn check_failure(&self) -> Result<()> {
+        match self.error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // whether a new send can start
+    pub fn check(&self) -> Result<()> {
+        self.check_failure()?;
+        if self.is_closing() {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+
+    // resolve once the connection has failed
+    pub async fn failed(&self) -> Error {
+        loop {
+            // register before checking, so a fail() in between is not missed
+            let notified = self.notify.notified();
+            if let Some(err) = self.error() {
+                return err;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Default for ConnState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,120 @@
+//! Error type of the crate.
+
+use crate::types::{cq::WCStatus, qp::Status};
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // no RDMA device available, or ibv_open_device/ibv_query_* failed
+    DeviceOpen(io::Error),
+    // ibv_alloc_pd failed
+    AllocPd(io::Error),
+    // ibv_create_cq failed
+    CreateCq(io::Error),
+    // ibv_create_qp failed
+    CreateQp(io::Error),
+    // ibv_reg_mr failed
+    RegMr(io::Error),
+    // ibv_modify_qp failed while moving the QP to `state`
+    ModifyQp { state: Status, source: io::Error },
+    // ibv_query_qp failed
+    QueryQp(io::Error),
+    // the QP is not in a state that allows `op`
+    InvalidQpState { state: Status, op: &'static str },
+    // ibv_post_send/ibv_post_recv failed
+    PostWr(io::Error),
+    // ibv_poll_cq failed
+    PollCq(io::Error),
+    // the remote side sent something we can't decode during the handshake
+    Handshake(bincode::Error),
+    // the remote side speaks another protocol version or sent an invalid message
+    Protocol(String),
+    // the remote side (or the local daemon) has gone away
+    Disconnected,
+    // the Conn is closing, or the remote side has closed it
+    Closed,
+    // a work request completed with an error status
+    WorkCompletion(WCStatus),
+    // the configuration can't be satisfied
+    InvalidConfig(String),
+    // the message exceeds the size settled with the remote side
+    MessageTooLarge { size: usize, max: u32 },
+    // the two sides can't resume the connection after an error
+    Recovery(String),
+    // a librdmacm call failed, or the remote side rejected the connection
+    Cm(io::Error),
+    // the out-of-band channel failed
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DeviceOpen(e) => write!(f, "open device error: {}", e),
+            Error::AllocPd(e) => write!(f, "alloc pd error: {}", e),
+            Error::CreateCq(e) => write!(f, "create cq error: {}", e),
+            Error::CreateQp(e) => write!(f, "create qp error: {}", e),
+            Error::RegMr(e) => write!(f, "register mr error: {}", e),
+            Error::ModifyQp { state, source } => {
+                write!(f, "modify qp to {:?} error: {}", state, source)
+            }
+            Error::QueryQp(e) => write!(f, "query qp error: {}", e),
+            Error::InvalidQpState { state, op } => write!(f, "qp is {:?}, can't {}", state, op),
+            Error::PostWr(e) => write!(f, "post wr error: {}", e),
+            Error::PollCq(e) => write!(f, "poll cq error: {}", e),
+            Error::Handshake(e) => write!(f, "handshake error: {}", e),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Disconnected => write!(f, "connection disconnected"),
+            Error::Closed => write!(f, "connection closed"),
+            Error::WorkCompletion(status) => write!(f, "work completion error: {:?}", status),
+            Error::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            Error::MessageTooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the max of {}", size, max)
+            }
+            Error::Recovery(msg) => write!(f, "recovery error: {}", msg),
+            Error::Cm(e) => write!(f, "rdma_cm error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DeviceOpen(e)
+            | Error::AllocPd(e)
+            | Error::CreateCq(e)
+            | Error::CreateQp(e)
+            | Error::RegMr(e)
+            | Error::QueryQp(e)
+            | Error::PostWr(e)
+            | Error::PollCq(e)
+            | Error::Cm(e)
+            | Error::Io(e) => Some(e),
+            Error::ModifyQp { source, .. } => Some(source),
+            Error::Handshake(e) => Some(e.as_ref()),
+            Error::Disconnected
+            | Error::Closed
+            | Error::WorkCompletion(_)
+            | Error::InvalidConfig(_)
+            | Error::MessageTooLarge { .. }
+            | Error::Recovery(_)
+            | Error::Protocol(_)
+            | Error::InvalidQpState { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Handshake(e)
+    }
+}
@@ -1,2 +1,5 @@
 pub mod connection;
+pub mod error;
 pub mod types;
+
+pub use error::{Error, Result};
@@ -0,0 +1,306 @@
+//! the out-of-band channel the handshake frames travel over before the QP is connected.
+//!
+//! a bootstrap moves whole frames, see handshake for their layout. the stream
+//! transports delimit them by the length in the frame header.
+
+use super::handshake::{frame_len, HEADER_LEN};
+use crate::error::{Error, Result};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// send and receive framed bytes to and from the remote side, in order
+pub trait Bootstrap: Send + Sync {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    // the next frame from the remote side
+    fn recv(&self) -> BoxFuture<'_, Result<Vec<u8>>>;
+}
+
+// frames over a byte stream, e.g. tcp or a unix domain socket
+pub struct StreamBootstrap<S> {
+    reader: Mutex<ReadHalf<S>>,
+    writer: Mutex<WriteHalf<S>>,
+}
+
+pub type TcpBootstrap = StreamBootstrap<TcpStream>;
+pub type UnixBootstrap = StreamBootstrap<UnixStream>;
+
+impl<S: AsyncRead + AsyncWrite> StreamBootstrap<S> {
+    pub fn new(stream: S) -> Self {
+        let (reader, writer) = split(stream);
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send> Bootstrap for StreamBootstrap<S> {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.writer.lock().await.write_all(frame).await?;
+            Ok(())
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut reader = self.reader.lock().await;
+            let mut frame = vec![0u8; HEADER_LEN];
+            reader.read_exact(&mut frame).await?;
+            // garbage is rejected before a payload buffer is allocated for it
+            let length = frame_len(&frame)?;
+            frame.resize(HEADER_LEN + length, 0);
+            reader.read_exact(&mut frame[HEADER_LEN..]).await?;
+            Ok(frame)
+        })
+    }
+}
+
+// frames over channels within the process, for tests and in-process peers
+pub struct MemBootstrap {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: Mutex<UnboundedReceiver<Vec<u8>>>,
+}
+
+impl MemBootstrap {
+    // the two ends of a channel
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = unbounded_channel();
+        let (b_tx, b_rx) = unbounded_channel();
+        (
+            Self {
+                tx: a_tx,
+                rx: Mutex::new(b_rx),
+            },
+            Self {
+                tx: b_tx,
+                rx: Mutex::new(a_rx),
+            },
+        )
+    }
+}
+
+impl Bootstrap for MemBootstrap {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.tx
+                .send(frame.to_vec())
+                .map_err(|_| Error::Disconnected)
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move { self.rx.lock().await.recv().await.ok_or(Error::Disconnected) })
+    }
+}
+
+// frames as files in a directory both sides can reach, e.g. a shared file system
+// of an MPI-style launcher. frame n from `local` to `remote` is the file
+// `session.local-remote.n`, it is removed once received.
+pub struct FileBootstrap {
+    dir: PathBuf,
+    session: String,
+    local: String,
+    remote: String,
+    sent: AtomicU64,
+    // the seq of the next frame, held while waiting for it
+    received: Mutex<u64>,
+    interval: Duration,
+}
+
+impl FileBootstrap {
+    // local and remote name the two sides, each side passes them swapped. session
+    // must be new for each run, e.g. a job id from the launcher, so the frames left
+    // behind by an aborted run in the same directory are never read.
+    pub fn new(dir: impl AsRef<Path>, session: &str, local: &str, remote: &str) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            session: session.to_owned(),
+            local: local.to_owned(),
+            remote: remote.to_owned(),
+            sent: AtomicU64::new(0),
+            received: Mutex::new(0),
+            interval: Duration::from_millis(10),
+        }
+    }
+
+    // how often recv looks for the next frame
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    fn path(&self, from: &str, to: &str, seq: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}-{}.{}", self.session, from, to, seq))
+    }
+}
+
+impl Bootstrap for FileBootstrap {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let seq = self.sent.fetch_add(1, Ordering::AcqRel);
+            let path = self.path(&self.local, &self.remote, seq);
+            // the remote side never sees a partially written frame
+            let tmp = path.with_extension(format!("{}.tmp", seq));
+            tokio::fs::write(&tmp, frame).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move {
+            // a cancelled recv leaves seq to the next one
+            let mut received = self.received.lock().await;
+            let path = self.path(&self.remote, &self.local, *received);
+            loop {
+                match tokio::fs::read(&path).await {
+                    Ok(frame) => {
+                        *received += 1;
+                        tokio::fs::remove_file(&path).await?;
+                        return Ok(frame);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        tokio::time::sleep(self.interval).await
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::handshake::{encode_frame, Kind, Limits};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::io::duplex;
+
+    fn frames() -> Vec<Vec<u8>> {
+        (1..=3)
+            .map(|rqe_count| {
+                let limits = Limits {
+                    rqe_count,
+                    ..Limits::default()
+                };
+                encode_frame(Kind::Buffer, &limits).unwrap()
+            })
+            .collect()
+    }
+
+    // the frames sent by one side are received in order by the other, both ways
+    async fn exchange(a: &dyn Bootstrap, b: &dyn Bootstrap) {
+        for frame in frames() {
+            a.send(&frame).await.unwrap();
+        }
+        for frame in frames().iter().rev() {
+            b.send(frame).await.unwrap();
+        }
+        for frame in frames() {
+            assert_eq!(b.recv().await.unwrap(), frame);
+        }
+        for frame in frames().iter().rev() {
+            assert_eq!(&a.recv().await.unwrap(), frame);
+        }
+    }
+
+    #[tokio::test]
+    async fn mem_pair() {
+        let (a, b) = MemBootstrap::pair();
+        exchange(&a, &b).await;
+        drop(b);
+        assert!(matches!(
+            a.send(&frames()[0]).await,
+            Err(Error::Disconnected)
+        ));
+        assert!(matches!(a.recv().await, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn stream() {
+        let (a, b) = duplex(4096);
+        let (a, b) = (StreamBootstrap::new(a), StreamBootstrap::new(b));
+        exchange(&a, &b).await;
+    }
+
+    #[tokio::test]
+    async fn stream_rejects_garbage() {
+        let (mut a, b) = duplex(1024);
+        let b = StreamBootstrap::new(b);
+        let mut frame = frames().remove(0);
+        frame[0] ^= 0xff;
+        a.write_all(&frame).await.unwrap();
+        assert!(matches!(b.recv().await, Err(Error::Protocol(_))));
+    }
+
+    // a fresh directory for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!(
+            "bootstrap-{}-{}-{}",
+            name,
+            std::process::id(),
+            nanos
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn file_pair(dir: &Path, session: &str) -> (FileBootstrap, FileBootstrap) {
+        let interval = Duration::from_millis(1);
+        (
+            FileBootstrap::new(dir, session, "a", "b").interval(interval),
+            FileBootstrap::new(dir, session, "b", "a").interval(interval),
+        )
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let dir = temp_dir("file");
+        let (a, b) = file_pair(&dir, "run");
+        exchange(&a, &b).await;
+        // every frame is removed once received
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_ignores_an_aborted_run() {
+        let dir = temp_dir("aborted");
+        // the frames an aborted run has left for b
+        let (stale, _) = file_pair(&dir, "old");
+        stale.send(b"stale").await.unwrap();
+        stale.send(b"stale").await.unwrap();
+        let (a, b) = file_pair(&dir, "new");
+        exchange(&a, &b).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_recv_is_cancel_safe() {
+        let dir = temp_dir("cancel");
+        let (a, b) = file_pair(&dir, "run");
+        let waited = tokio::time::timeout(Duration::from_millis(5), b.recv()).await;
+        assert!(waited.is_err());
+        // the cancelled recv hasn't taken the first frame
+        exchange(&a, &b).await;
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
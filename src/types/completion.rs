@@ -0,0 +1,59 @@
+//! waiting on the completion daemon without polling.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::Notify;
+
+// carried by the wr_id of a send request, completed when its wc is polled,
+// either with success or flushed.
+pub struct Completion {
+    pending: AtomicBool,
+    notify: Notify,
+}
+
+impl Completion {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            pending: AtomicBool::new(true),
+            notify: Notify::new(),
+        })
+    }
+
+    // a reference for the wr_id, taken back by from_wr_id once the wc is polled
+    pub fn wr_id(self: &Arc<Self>) -> u64 {
+        Arc::into_raw(self.clone()) as u64
+    }
+
+    // # Safety
+    // wr_id must come from Completion::wr_id and be taken back only once
+    pub unsafe fn from_wr_id(wr_id: u64) -> Arc<Self> {
+        Arc::from_raw(wr_id as *const Self)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn complete(&self) {
+        self.pending.store(false, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    pub async fn wait(&self) {
+        wait_until(&self.notify, || !self.is_pending()).await
+    }
+}
+
+// wait until ready holds, it is checked again each time notify wakes the waiters
+pub async fn wait_until(notify: &Notify, mut ready: impl FnMut() -> bool) {
+    loop {
+        // register before checking, so a notify_waiters in between is not missed
+        let notified = notify.notified();
+        if ready() {
+            return;
+        }
+        notified.await;
+    }
+}
@@ -0,0 +1,62 @@
+use super::device::{default_device, Device, DeviceSelector};
+use super::mr::MR;
+use super::pd::PD;
+use crate::error::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// a device and the protection domain shared by every QP and buffer on it,
+// a MR registered once can be used on any Conn of the same Context.
+pub struct Context {
+    device: Arc<Device>,
+    pd: Arc<PD>,
+    // a reactor reads the async events of the device, see claim_async_events
+    async_events: AtomicBool,
+}
+
+impl Context {
+    pub fn new(device: Arc<Device>) -> Result<Arc<Self>> {
+        let pd = Arc::new(PD::new(device.clone())?);
+        Ok(Arc::new(Self {
+            device,
+            pd,
+            async_events: AtomicBool::new(false),
+        }))
+    }
+
+    // open the first device
+    pub fn open_default() -> Result<Arc<Self>> {
+        Self::new(Arc::new(Device::new(default_device()?)?))
+    }
+
+    // open the device, port and gid chosen by selector
+    pub fn open(selector: &DeviceSelector) -> Result<Arc<Self>> {
+        Self::new(Arc::new(Device::open(selector)?))
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn pd(&self) -> &Arc<PD> {
+        &self.pd
+    }
+
+    // an event is consumed by the one reader that gets it, so only the first caller
+    // reads them, true for that one
+    pub(crate) fn claim_async_events(&self) -> bool {
+        !self.async_events.swap(true, Ordering::AcqRel)
+    }
+
+    // the reader is gone, the next reactor on the Context takes over
+    pub(crate) fn release_async_events(&self) {
+        self.async_events.store(false, Ordering::Release);
+    }
+
+    // register a buffer in the shared PD, the MR owns it until it is deregistered
+    pub fn register(&self, data: Vec<u8>) -> Result<MR> {
+        MR::new(&self.pd, data)
+    }
+}
@@ -1,12 +1,18 @@
-use super::default::MAX_CQE;
+use super::default::{MAX_CQE, MAX_UNACKED_CQ_EVENTS};
 use super::device::Device;
+use crate::error::{Error, Result};
 use rdma_sys::*;
-use std::io::{Error, Result};
 use std::{
     fmt::{self, Debug},
+    io,
+    os::unix::io::RawFd,
     ptr::NonNull,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
 };
+use tokio::io::{unix::AsyncFd, Interest};
 
 // Define a `CQ` struct
 pub struct CQ {
@@ -16,26 +22,53 @@ pub struct CQ {
     pub device: Arc<Device>,
     // A pointer to an `ibv_comp_channel` struct wrapped in `Option` type
     channel: Option<NonNull<ibv_comp_channel>>,
+    // The non-blocking fd of the channel registered in the tokio reactor
+    event_fd: Option<AsyncFd<RawFd>>,
+    // Number of events got from the channel but not acked yet
+    unacked: AtomicU32,
+    // The work requests the QPs on this CQ may have outstanding, see reserve
+    committed: Mutex<u32>,
 }
 
 unsafe impl Send for CQ {}
 unsafe impl Sync for CQ {}
 
 impl CQ {
-    pub fn new(device: Arc<Device>, with_channel: bool) -> Self {
+    // with_channel must be called within a tokio runtime, the channel fd is registered in it.
+    pub fn new(device: Arc<Device>, with_channel: bool) -> Result<Self> {
         let mut channel: *mut ibv_comp_channel = std::ptr::null_mut();
         if with_channel {
             channel = unsafe { ibv_create_comp_channel(device.inner()) };
+            if channel.is_null() {
+                return Err(Error::CreateCq(io::Error::last_os_error()));
+            }
         }
-        let cq = NonNull::new(unsafe {
-            ibv_create_cq(device.inner(), MAX_CQE, std::ptr::null_mut(), channel, 0)
-        })
-        .unwrap();
-        Self {
+        let size = MAX_CQE.min(device.caps().max_cqe);
+        let cq = match NonNull::new(unsafe {
+            ibv_create_cq(device.inner(), size, std::ptr::null_mut(), channel, 0)
+        }) {
+            Some(cq) => cq,
+            None => {
+                let err = io::Error::last_os_error();
+                if !channel.is_null() {
+                    unsafe { ibv_destroy_comp_channel(channel) };
+                }
+                return Err(Error::CreateCq(err));
+            }
+        };
+        let mut cq = Self {
             inner: cq,
             device: device.clone(),
             channel: NonNull::new(channel),
+            event_fd: None,
+            unacked: AtomicU32::new(0),
+            committed: Mutex::new(0),
+        };
+        if with_channel {
+            // on failure, drop(cq) destroys both the cq and the channel
+            cq.event_fd = Some(register_fd(unsafe { (*channel).fd }).map_err(Error::CreateCq)?);
         }
+        Ok(cq)
     }
 
     pub fn inner(&self) -> *mut ibv_cq {
@@ -46,8 +79,34 @@ impl CQ {
         self.device.inner()
     }
 
-    pub fn channel(&self) -> *mut ibv_comp_channel {
-        self.channel.unwrap().as_ptr()
+    pub fn channel(&self) -> Option<*mut ibv_comp_channel> {
+        self.channel.map(|c| c.as_ptr())
+    }
+
+    // The number of wcs the CQ holds
+    pub fn capacity(&self) -> u32 {
+        unsafe { (*self.inner()).cqe as u32 }
+    }
+
+    // Make room for the wcs of a QP with `wrs` work requests, an overrun fails every
+    // QP on the CQ. The CQ grows up to max_cqe of the device, the QP is rejected beyond.
+    pub fn reserve(&self, wrs: u32) -> Result<()> {
+        let mut committed = self.committed.lock().unwrap_or_else(|e| e.into_inner());
+        let max_cqe = self.device.caps().max_cqe;
+        if let Some(size) = grow(*committed, wrs, self.capacity(), max_cqe)? {
+            let ret = unsafe { ibv_resize_cq(self.inner(), size as i32) };
+            if ret != 0 {
+                return Err(Error::CreateQp(io::Error::from_raw_os_error(ret)));
+            }
+        }
+        *committed += wrs;
+        Ok(())
+    }
+
+    // The QP holding `wrs` of reserve is destroyed
+    pub fn release(&self, wrs: u32) {
+        let mut committed = self.committed.lock().unwrap_or_else(|e| e.into_inner());
+        *committed = committed.saturating_sub(wrs);
     }
 
     // Define a `poll_wc` method that takes a `u32` as input and returns a `Vec` of `WC`
@@ -63,26 +122,57 @@ impl CQ {
         let num_poll = unsafe { ibv_poll_cq(self.inner(), num_entries as i32, &mut wcs[0].0) };
         // If `num_poll` is less than zero, return an `Error` with the last operating system error
         if num_poll < 0 {
-            return Err(Error::last_os_error());
+            return Err(Error::PollCq(io::Error::last_os_error()));
         }
         // Set the length of `wcs` to the number of polled work completions and return `Ok` with `wcs`
         unsafe { wcs.set_len(num_poll as usize) };
         Ok(wcs)
     }
 
+    // Arm the CQ, the next completion will generate an event on the channel
     pub fn req_notify(&self, solicited_only: bool) -> Result<()> {
         let ret = unsafe { ibv_req_notify_cq(self.inner(), solicited_only as i32) };
-        if ret < 0 {
-            return Err(Error::last_os_error());
+        if ret != 0 {
+            return Err(Error::PollCq(io::Error::last_os_error()));
         }
         Ok(())
     }
 
-    pub fn get_event(&self) -> i32 {
-        unsafe { ibv_get_cq_event(self.channel(), &mut self.inner(), std::ptr::null_mut()) }
+    // Get an event from the channel without blocking, `WouldBlock` if there is none
+    pub fn get_event(&self) -> io::Result<()> {
+        let channel = self.channel().ok_or_else(no_channel)?;
+        let mut cq = std::ptr::null_mut();
+        let mut cq_context = std::ptr::null_mut();
+        if unsafe { ibv_get_cq_event(channel, &mut cq, &mut cq_context) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // events must be acked before the cq is destroyed, ack them in batch as ack takes a lock
+        if self.unacked.fetch_add(1, Ordering::AcqRel) + 1 >= MAX_UNACKED_CQ_EVENTS {
+            self.ack_event(self.unacked.swap(0, Ordering::AcqRel));
+        }
+        Ok(())
+    }
+
+    // Wait until the channel is readable and consume one event
+    pub async fn wait_event(&self) -> Result<()> {
+        let event_fd = self
+            .event_fd
+            .as_ref()
+            .ok_or_else(|| Error::PollCq(no_channel()))?;
+        loop {
+            let mut guard = event_fd.readable().await.map_err(Error::PollCq)?;
+            // WouldBlock clears the readiness and waits again
+            match guard.try_io(|_| self.get_event()) {
+                Ok(res) => return res.map_err(Error::PollCq),
+                Err(_would_block) => continue,
+            }
+        }
     }
 
     pub fn ack_event(&self, nevents: u32) {
+        if nevents == 0 {
+            return;
+        }
         unsafe {
             ibv_ack_cq_events(self.inner(), nevents);
         }
@@ -91,13 +181,53 @@ impl CQ {
 
 impl Drop for CQ {
     fn drop(&mut self) {
+        // deregister the fd before the channel is destroyed
+        self.event_fd.take();
+        self.ack_event(self.unacked.swap(0, Ordering::AcqRel));
         unsafe {
             ibv_destroy_cq(self.inner());
         }
+        if let Some(channel) = self.channel() {
+            unsafe {
+                ibv_destroy_comp_channel(channel);
+            }
+        }
+    }
+}
+
+// the size a CQ of `capacity` holding the wcs of `committed` work requests has to
+// grow to for `wrs` more, None if they fit
+fn grow(committed: u32, wrs: u32, capacity: u32, max_cqe: i32) -> Result<Option<u32>> {
+    let need = committed.saturating_add(wrs);
+    if need <= capacity {
+        return Ok(None);
+    }
+    if need as i64 > max_cqe as i64 {
+        return Err(Error::CreateQp(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "the cq can't hold the wcs of {} more work requests, {} of max_cqe {} are taken",
+                wrs, committed, max_cqe
+            ),
+        )));
+    }
+    Ok(Some(need))
+}
+
+// set the fd of an event channel non-blocking and register it in the tokio reactor
+pub(crate) fn register_fd(fd: RawFd) -> io::Result<AsyncFd<RawFd>> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
     }
+    AsyncFd::with_interest(fd, Interest::READABLE)
 }
 
-pub fn create_cq(device: &Device, size: i32, with_channel: bool) -> NonNull<ibv_cq> {
+fn no_channel() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "cq is created without channel")
+}
+
+pub fn create_cq(device: &Device, size: i32, with_channel: bool) -> Result<NonNull<ibv_cq>> {
     let cq = if with_channel {
         unsafe {
             ibv_create_cq(
@@ -119,7 +249,7 @@ pub fn create_cq(device: &Device, size: i32, with_channel: bool) -> NonNull<ibv_
             )
         }
     };
-    NonNull::new(cq).unwrap()
+    NonNull::new(cq).ok_or_else(|| Error::CreateCq(io::Error::last_os_error()))
 }
 
 pub struct WC(ibv_wc);
@@ -152,6 +282,11 @@ impl WC {
     pub fn byte_len(&self) -> u32 {
         self.0.byte_len
     }
+
+    // the local QP this wc belongs to
+    pub fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
 }
 
 impl Debug for WC {
@@ -166,7 +301,7 @@ impl Debug for WC {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WCStatus {
     // 0: IBV_WC_SUCCESS - Work Request completed successfully.
     Success,
@@ -287,3 +422,42 @@ impl From<u32> for WCFlag {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::qp::QPConfig;
+
+    // max_cqe of soft-RoCE and of a ConnectX-5
+    const RXE_MAX_CQE: i32 = 32768;
+    const MLX5_MAX_CQE: i32 = 4194303;
+
+    // the number of default QPs a shard CQ of MAX_CQE admits
+    fn admitted(max_cqe: i32) -> u32 {
+        let wrs = QPConfig::default().cap.wrs();
+        let (mut committed, mut capacity, mut n) = (0, MAX_CQE.min(max_cqe) as u32, 0);
+        while let Ok(size) = grow(committed, wrs, capacity, max_cqe) {
+            capacity = size.unwrap_or(capacity);
+            committed += wrs;
+            n += 1;
+        }
+        assert!(committed <= capacity);
+        n
+    }
+
+    #[test]
+    fn default_qps_share_a_shard() {
+        assert!(admitted(RXE_MAX_CQE) >= 32);
+        assert!(admitted(MLX5_MAX_CQE) >= 4000);
+    }
+
+    #[test]
+    fn grow_only_beyond_the_capacity() {
+        assert_eq!(grow(0, 100, 100, 1000).unwrap(), None);
+        assert_eq!(grow(50, 100, 100, 1000).unwrap(), Some(150));
+        assert_eq!(grow(900, 100, 100, 1000).unwrap(), Some(1000));
+        assert!(matches!(grow(901, 100, 100, 1000), Err(Error::CreateQp(_))));
+        // no overflow near u32::MAX
+        assert!(grow(u32::MAX, 1, 100, i32::MAX).is_err());
+    }
+}
@@ -1,13 +1,28 @@
+use std::time::Duration;
+
+pub static DEFAULT_PORT: u8 = 1;
 pub static DEFAULT_GID_INDEX: u8 = 1;
 
-// cq size
+// cq size, bounded by max_cqe of the device. a shard CQ grows up to max_cqe as QPs
+// are created on it.
 pub static MAX_CQE: i32 = 32767;
-pub static MAX_QP_WR: u32 = 16384;
+// the depth of each queue of a default QP, both take room in the shared CQ, so a
+// shard of MAX_CQE holds 31 of them before it has to grow
+pub static MAX_QP_WR: u32 = 512;
 
-// seems like this is the max number of outstanding requests in RDMA-RoCE
+// an RQE is taken by each message in flight, the recv queue keeps them all posted
 pub static DEFAULT_RQE_COUNT: u32 = MAX_QP_WR;
 
 pub static DEFAULT_SEND_BUFFER_SIZE: usize = 64 * 1024 * 1024;
 pub static DEFAULT_RECV_BUFFER_SIZE: usize = 64 * 1024 * 1024;
 
+// half of the default buffers, the peer may settle on less
+pub static DEFAULT_MAX_MSG_SIZE: u32 = 32 * 1024 * 1024;
+
 pub static MIN_LENGTH_TO_NOTIFY_RELEASE: u32 = 8 * 1024;
+
+// a peer that goes silent during the handshake is given up on after this
+pub static DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// ibv_ack_cq_events takes a mutex, so cq events are acked in batch
+pub static MAX_UNACKED_CQ_EVENTS: u32 = 64;
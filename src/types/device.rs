@@ -1,39 +1,210 @@
+use super::default::{DEFAULT_GID_INDEX, DEFAULT_PORT};
+use crate::error::{Error, Result};
 use rdma_sys::*;
-use std::ptr::NonNull;
+use serde::Serialize;
+use std::{
+    ffi::CStr,
+    fs, io,
+    net::{IpAddr, Ipv6Addr},
+    ptr::NonNull,
+};
 
 pub struct Device {
     pub context: NonNull<ibv_context>,
     pub port_attr: ibv_port_attr,
     pub device_attr: ibv_device_attr,
+    // the port and the gid index used by the QPs on this device
+    port: u8,
+    gid_index: u8,
 }
 
 impl Device {
-    pub fn new(context: NonNull<ibv_context>) -> Self {
+    // use port 1 and the default gid index
+    pub fn new(context: NonNull<ibv_context>) -> Result<Self> {
+        Self::with_port(context, DEFAULT_PORT, DEFAULT_GID_INDEX)
+    }
+
+    pub fn with_port(context: NonNull<ibv_context>, port: u8, gid_index: u8) -> Result<Self> {
         let mut port_attr = unsafe { std::mem::zeroed() };
-        unsafe { rdma_sys::___ibv_query_port(context.as_ptr(), 1, &mut port_attr) };
         let mut device_attr = unsafe { std::mem::zeroed() };
-        unsafe { rdma_sys::ibv_query_device(context.as_ptr(), &mut device_attr) };
-        Self {
+        if unsafe { rdma_sys::___ibv_query_port(context.as_ptr(), port, &mut port_attr) } != 0
+            || unsafe { rdma_sys::ibv_query_device(context.as_ptr(), &mut device_attr) } != 0
+        {
+            let err = io::Error::last_os_error();
+            unsafe { ibv_close_device(context.as_ptr()) };
+            return Err(Error::DeviceOpen(err));
+        }
+        Ok(Self {
             context,
             port_attr,
             device_attr,
+            port,
+            gid_index,
+        })
+    }
+
+    // open the device, port and gid chosen by selector
+    pub fn open(selector: &DeviceSelector) -> Result<Self> {
+        let context = match &selector.name {
+            Some(name) => open_device(name)?,
+            None => default_device()?,
+        };
+        let mut device = Self::with_port(context, selector.port, DEFAULT_GID_INDEX)?;
+        device.gid_index = match selector.gid {
+            GidSelector::Index(idx) => idx,
+            GidSelector::Ip(ip) => device.find_gid(ip)?,
+        };
+        if device.gid_index as i32 >= device.port_attr.gid_tbl_len {
+            return Err(Error::InvalidConfig(format!(
+                "gid index {} is out of the gid table of {} port {}",
+                device.gid_index,
+                device.name(),
+                device.port
+            )));
         }
+        Ok(device)
     }
 
     pub fn inner(&self) -> *mut ibv_context {
         self.context.as_ptr()
     }
 
+    // e.g. rxe_0, mlx5_1
+    pub fn name(&self) -> String {
+        unsafe { device_name((*self.inner()).device) }
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn gid_index(&self) -> u8 {
+        self.gid_index
+    }
+
+    // the mtu of the selected port
+    pub fn active_mtu(&self) -> Mtu {
+        Mtu::from(self.port_attr.active_mtu)
+    }
+
     pub fn lid(&self) -> u16 {
         self.port_attr.lid
     }
 
     pub fn gid(&self, idx: i8) -> [u8; 16] {
+        self.query_gid(self.port, idx as u8).unwrap_or_default()
+    }
+
+    fn query_gid(&self, port: u8, idx: u8) -> Option<[u8; 16]> {
         let mut gid = unsafe { std::mem::zeroed::<ibv_gid>() };
-        unsafe {
-            ibv_query_gid(self.inner(), 1, idx as i32, &mut gid);
+        if unsafe { ibv_query_gid(self.inner(), port, idx as i32, &mut gid) } != 0 {
+            return None;
+        }
+        Some(unsafe { gid.raw })
+    }
+
+    // the gid used by the QPs on this device
+    pub fn local_gid(&self) -> [u8; 16] {
+        self.gid(self.gid_index as i8)
+    }
+
+    // the RoCE type of a gid reported by the kernel, e.g. "RoCE v2", "IB/RoCE v1"
+    pub fn gid_type(&self, idx: u8) -> Option<String> {
+        gid_type(&self.name(), self.port, idx)
+    }
+
+    // the gid index whose gid is ip, a RoCE v2 gid is preferred
+    pub fn find_gid(&self, ip: IpAddr) -> Result<u8> {
+        let target = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let mut found = None;
+        for idx in 0..self.port_attr.gid_tbl_len.min(u8::MAX as i32 + 1) as u16 {
+            let idx = idx as u8;
+            if self.gid(idx as i8) != target {
+                continue;
+            }
+            if self.gid_type(idx).as_deref() == Some("RoCE v2") {
+                return Ok(idx);
+            }
+            found.get_or_insert(idx);
+        }
+        found.ok_or_else(|| {
+            Error::DeviceOpen(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no gid of {} port {} matches {}",
+                    self.name(),
+                    self.port,
+                    ip
+                ),
+            ))
+        })
+    }
+
+    pub fn node_guid(&self) -> u64 {
+        u64::from_be(unsafe { ibv_get_device_guid((*self.inner()).device) })
+    }
+
+    pub fn transport(&self) -> Transport {
+        Transport::from(unsafe { (*(*self.inner()).device).transport_type })
+    }
+
+    pub fn caps(&self) -> DeviceCaps {
+        DeviceCaps::from(&self.device_attr)
+    }
+
+    // every port of the device, numbered from 1
+    pub fn ports(&self) -> Result<Vec<PortInfo>> {
+        (1..=self.device_attr.phys_port_cnt)
+            .map(|port| self.query_port(port))
+            .collect()
+    }
+
+    pub fn query_port(&self, port: u8) -> Result<PortInfo> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_port_attr>() };
+        if unsafe { rdma_sys::___ibv_query_port(self.inner(), port, &mut attr) } != 0 {
+            return Err(Error::DeviceOpen(io::Error::last_os_error()));
         }
-        unsafe { gid.raw }
+        let name = self.name();
+        let gids = (0..attr.gid_tbl_len.min(u8::MAX as i32 + 1) as u16)
+            .filter_map(|idx| {
+                let idx = idx as u8;
+                let gid = self.query_gid(port, idx)?;
+                // unused entries are all zero
+                (gid != [0; 16]).then(|| GidEntry {
+                    index: idx,
+                    gid: Ipv6Addr::from(gid),
+                    gid_type: gid_type(&name, port, idx),
+                })
+            })
+            .collect();
+        Ok(PortInfo {
+            port,
+            state: PortState::from(attr.state),
+            active_mtu: Mtu::from(attr.active_mtu),
+            max_mtu: Mtu::from(attr.max_mtu),
+            link_layer: LinkLayer::from(attr.link_layer),
+            lid: attr.lid,
+            gids,
+        })
+    }
+
+    // every RDMA device on the host, for diagnostics. a device that can't be opened
+    // or queried is reported in its place and doesn't hide the others.
+    pub fn list() -> Result<Vec<std::result::Result<DeviceInfo, DeviceError>>> {
+        let list = DeviceList::new()?;
+        Ok(list
+            .devices()
+            .iter()
+            .map(|device| {
+                DeviceInfo::query(*device).map_err(|error| DeviceError {
+                    name: unsafe { device_name(*device) },
+                    error,
+                })
+            })
+            .collect())
     }
 
     pub fn max_qp_wr(&self) -> i32 {
@@ -56,8 +227,370 @@ impl Drop for Device {
 unsafe impl Send for Device {}
 unsafe impl Sync for Device {}
 
-pub fn default_device() -> NonNull<ibv_context> {
-    let mut x = 1;
-    let context = unsafe { ibv_open_device(*ibv_get_device_list(&mut x)) };
-    NonNull::new(context).unwrap()
+// which device, port and gid the QPs use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSelector {
+    // None for the first device
+    pub name: Option<String>,
+    pub port: u8,
+    pub gid: GidSelector,
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        Self {
+            name: None,
+            port: DEFAULT_PORT,
+            gid: GidSelector::Index(DEFAULT_GID_INDEX),
+        }
+    }
+}
+
+impl DeviceSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn port(mut self, port: u8) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn gid_index(mut self, idx: u8) -> Self {
+        self.gid = GidSelector::Index(idx);
+        self
+    }
+
+    // use the gid of the local ip address, for RoCE v2
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.gid = GidSelector::Ip(ip);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GidSelector {
+    Index(u8),
+    Ip(IpAddr),
+}
+
+pub fn default_device() -> Result<NonNull<ibv_context>> {
+    let list = DeviceList::new()?;
+    let device = list.devices().first().ok_or_else(|| {
+        Error::DeviceOpen(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no RDMA device found",
+        ))
+    })?;
+    // the opened context stays valid after the list is freed
+    open(*device)
+}
+
+// open the device by name, e.g. rxe_0, mlx5_1
+pub fn open_device(name: &str) -> Result<NonNull<ibv_context>> {
+    let list = DeviceList::new()?;
+    let device = list
+        .devices()
+        .iter()
+        .find(|device| unsafe { device_name(**device) } == name)
+        .ok_or_else(|| {
+            Error::DeviceOpen(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("RDMA device {} not found", name),
+            ))
+        })?;
+    open(*device)
+}
+
+fn open(device: *mut ibv_device) -> Result<NonNull<ibv_context>> {
+    let context = unsafe { ibv_open_device(device) };
+    NonNull::new(context).ok_or_else(|| Error::DeviceOpen(io::Error::last_os_error()))
+}
+
+// the result of ibv_get_device_list, freed on drop
+struct DeviceList {
+    list: *mut *mut ibv_device,
+    num: usize,
+}
+
+impl DeviceList {
+    fn new() -> Result<Self> {
+        let mut num = 0;
+        let list = unsafe { ibv_get_device_list(&mut num) };
+        if list.is_null() {
+            return Err(Error::DeviceOpen(io::Error::last_os_error()));
+        }
+        Ok(Self {
+            list,
+            num: num.max(0) as usize,
+        })
+    }
+
+    fn devices(&self) -> &[*mut ibv_device] {
+        unsafe { std::slice::from_raw_parts(self.list, self.num) }
+    }
+}
+
+impl Drop for DeviceList {
+    fn drop(&mut self) {
+        unsafe { ibv_free_device_list(self.list) };
+    }
+}
+
+unsafe fn device_name(device: *mut ibv_device) -> String {
+    CStr::from_ptr(ibv_get_device_name(device))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn gid_type(name: &str, port: u8, idx: u8) -> Option<String> {
+    let path = format!(
+        "/sys/class/infiniband/{}/ports/{}/gid_attrs/types/{}",
+        name, port, idx
+    );
+    fs::read_to_string(path).ok().map(|t| t.trim().to_owned())
+}
+
+// a device as reported by Device::list
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub node_guid: u64,
+    pub transport: Transport,
+    pub caps: DeviceCaps,
+    pub ports: Vec<PortInfo>,
+}
+
+impl DeviceInfo {
+    fn query(device: *mut ibv_device) -> Result<Self> {
+        let device = Device::new(open(device)?)?;
+        Ok(Self {
+            name: device.name(),
+            node_guid: device.node_guid(),
+            transport: device.transport(),
+            caps: device.caps(),
+            ports: device.ports()?,
+        })
+    }
+}
+
+// a device Device::list couldn't open or query
+#[derive(Debug)]
+pub struct DeviceError {
+    pub name: String,
+    pub error: Error,
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name, self.error)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortInfo {
+    pub port: u8,
+    pub state: PortState,
+    pub active_mtu: Mtu,
+    pub max_mtu: Mtu,
+    pub link_layer: LinkLayer,
+    pub lid: u16,
+    // the non-empty entries of the gid table
+    pub gids: Vec<GidEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GidEntry {
+    pub index: u8,
+    pub gid: Ipv6Addr,
+    // e.g. "RoCE v2", None if the kernel doesn't report it
+    pub gid_type: Option<String>,
+}
+
+// the limits and features of a device, from ibv_device_attr
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCaps {
+    pub fw_ver: String,
+    pub phys_port_cnt: u8,
+    pub max_qp: i32,
+    pub max_qp_wr: i32,
+    pub max_sge: i32,
+    pub max_cq: i32,
+    pub max_cqe: i32,
+    pub max_mr: i32,
+    pub max_mr_size: u64,
+    pub max_pd: i32,
+    pub max_srq: i32,
+    // outstanding RDMA read/atomic as target and as initiator per QP
+    pub max_qp_rd_atom: i32,
+    pub max_qp_init_rd_atom: i32,
+    pub atomic: AtomicCap,
+}
+
+impl From<&ibv_device_attr> for DeviceCaps {
+    fn from(attr: &ibv_device_attr) -> Self {
+        Self {
+            fw_ver: unsafe { CStr::from_ptr(attr.fw_ver.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            phys_port_cnt: attr.phys_port_cnt,
+            max_qp: attr.max_qp,
+            max_qp_wr: attr.max_qp_wr,
+            max_sge: attr.max_sge,
+            max_cq: attr.max_cq,
+            max_cqe: attr.max_cqe,
+            max_mr: attr.max_mr,
+            max_mr_size: attr.max_mr_size,
+            max_pd: attr.max_pd,
+            max_srq: attr.max_srq,
+            max_qp_rd_atom: attr.max_qp_rd_atom,
+            max_qp_init_rd_atom: attr.max_qp_init_rd_atom,
+            atomic: AtomicCap::from(attr.atomic_cap),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AtomicCap {
+    None,
+    // atomic operations are atomic within this device
+    Hca,
+    // atomic operations are atomic with respect to the CPU and other devices
+    Global,
+}
+
+impl From<ibv_atomic_cap::Type> for AtomicCap {
+    fn from(cap: ibv_atomic_cap::Type) -> Self {
+        match cap {
+            ibv_atomic_cap::IBV_ATOMIC_HCA => AtomicCap::Hca,
+            ibv_atomic_cap::IBV_ATOMIC_GLOB => AtomicCap::Global,
+            _ => AtomicCap::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Transport {
+    Infiniband,
+    Iwarp,
+    Usnic,
+    UsnicUdp,
+    Unspecified,
+    Unknown,
+}
+
+impl From<ibv_transport_type::Type> for Transport {
+    fn from(transport: ibv_transport_type::Type) -> Self {
+        match transport {
+            ibv_transport_type::IBV_TRANSPORT_IB => Transport::Infiniband,
+            ibv_transport_type::IBV_TRANSPORT_IWARP => Transport::Iwarp,
+            ibv_transport_type::IBV_TRANSPORT_USNIC => Transport::Usnic,
+            ibv_transport_type::IBV_TRANSPORT_USNIC_UDP => Transport::UsnicUdp,
+            ibv_transport_type::IBV_TRANSPORT_UNSPECIFIED => Transport::Unspecified,
+            _ => Transport::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PortState {
+    Nop,
+    Down,
+    Init,
+    Armed,
+    Active,
+    ActiveDefer,
+    Unknown,
+}
+
+impl From<ibv_port_state::Type> for PortState {
+    fn from(state: ibv_port_state::Type) -> Self {
+        match state {
+            ibv_port_state::IBV_PORT_NOP => PortState::Nop,
+            ibv_port_state::IBV_PORT_DOWN => PortState::Down,
+            ibv_port_state::IBV_PORT_INIT => PortState::Init,
+            ibv_port_state::IBV_PORT_ARMED => PortState::Armed,
+            ibv_port_state::IBV_PORT_ACTIVE => PortState::Active,
+            ibv_port_state::IBV_PORT_ACTIVE_DEFER => PortState::ActiveDefer,
+            _ => PortState::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LinkLayer {
+    Unspecified,
+    Infiniband,
+    // RoCE
+    Ethernet,
+}
+
+impl From<u8> for LinkLayer {
+    fn from(link_layer: u8) -> Self {
+        // IBV_LINK_LAYER_*
+        match link_layer {
+            1 => LinkLayer::Infiniband,
+            2 => LinkLayer::Ethernet,
+            _ => LinkLayer::Unspecified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Mtu {
+    Mtu256,
+    Mtu512,
+    Mtu1024,
+    Mtu2048,
+    Mtu4096,
+}
+
+impl Mtu {
+    pub fn bytes(&self) -> u32 {
+        match self {
+            Mtu::Mtu256 => 256,
+            Mtu::Mtu512 => 512,
+            Mtu::Mtu1024 => 1024,
+            Mtu::Mtu2048 => 2048,
+            Mtu::Mtu4096 => 4096,
+        }
+    }
+
+    pub fn from_bytes(bytes: u32) -> Option<Self> {
+        match bytes {
+            256 => Some(Mtu::Mtu256),
+            512 => Some(Mtu::Mtu512),
+            1024 => Some(Mtu::Mtu1024),
+            2048 => Some(Mtu::Mtu2048),
+            4096 => Some(Mtu::Mtu4096),
+            _ => None,
+        }
+    }
+
+    pub fn to_ibv(self) -> ibv_mtu::Type {
+        match self {
+            Mtu::Mtu256 => ibv_mtu::IBV_MTU_256,
+            Mtu::Mtu512 => ibv_mtu::IBV_MTU_512,
+            Mtu::Mtu1024 => ibv_mtu::IBV_MTU_1024,
+            Mtu::Mtu2048 => ibv_mtu::IBV_MTU_2048,
+            Mtu::Mtu4096 => ibv_mtu::IBV_MTU_4096,
+        }
+    }
+}
+
+impl From<ibv_mtu::Type> for Mtu {
+    fn from(mtu: ibv_mtu::Type) -> Self {
+        match mtu {
+            ibv_mtu::IBV_MTU_256 => Mtu::Mtu256,
+            ibv_mtu::IBV_MTU_512 => Mtu::Mtu512,
+            ibv_mtu::IBV_MTU_2048 => Mtu::Mtu2048,
+            ibv_mtu::IBV_MTU_4096 => Mtu::Mtu4096,
+            _ => Mtu::Mtu1024,
+        }
+    }
 }
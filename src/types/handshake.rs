@@ -0,0 +1,359 @@
+//! the messages exchanged over the bootstrap channel before and after the QP is connected.
+//!
+//! every message is framed as `magic | version | kind | length | payload`, the
+//! header fields are big endian and the payload is bincode.
+
+use super::default::{
+    DEFAULT_MAX_MSG_SIZE, DEFAULT_RECV_BUFFER_SIZE, DEFAULT_RQE_COUNT, DEFAULT_SEND_BUFFER_SIZE,
+};
+use super::mr::RemoteMR;
+use super::qp::{EndPoint, QP};
+use crate::error::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+// "IBVC"
+pub const MAGIC: u32 = 0x4942_5643;
+// bumped on any incompatible change of the messages below or of the imm,
+// 2: the imm carries the released position instead of the released length
+pub const PROTOCOL_VERSION: u16 = 2;
+// no message is near this size, a larger length means garbage
+pub const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+pub(crate) const HEADER_LEN: usize = 12;
+
+// the optional features a side supports, the handshake keeps the common ones
+pub mod features {
+    // Conn::recover
+    pub const RECOVERY: u64 = 1 << 0;
+
+    pub const ALL: u64 = RECOVERY;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Hello = 1,
+    Buffer = 2,
+    Resync = 3,
+}
+
+// the first message of each side
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub endpoint: EndPoint,
+    pub features: u64,
+}
+
+impl Hello {
+    pub fn new(endpoint: EndPoint) -> Self {
+        Self {
+            endpoint,
+            features: features::ALL,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.endpoint.validate()
+    }
+}
+
+// the buffers and limits one side advertises in exchange_recv_buf
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // size of the ring the remote side writes into
+    pub recv_buffer_size: u64,
+    // size of the ring messages are copied into before sending
+    pub send_buffer_size: u64,
+    // the largest message this side sends or accepts
+    pub max_msg_size: u32,
+    // RQEs kept posted, the remote side can't have more writes in flight
+    pub rqe_count: u32,
+    // the largest message sent inline, 0 for none
+    pub max_inline: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE as u64,
+            send_buffer_size: DEFAULT_SEND_BUFFER_SIZE as u64,
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
+            rqe_count: DEFAULT_RQE_COUNT,
+            max_inline: 0,
+        }
+    }
+}
+
+impl Limits {
+    pub fn validate(&self) -> Result<()> {
+        if self.recv_buffer_size == 0 || self.send_buffer_size == 0 {
+            return Err(Error::Protocol(format!(
+                "invalid buffer sizes {}/{}",
+                self.send_buffer_size, self.recv_buffer_size
+            )));
+        }
+        if self.max_msg_size == 0 || self.rqe_count == 0 {
+            return Err(Error::Protocol(format!(
+                "invalid max_msg_size {} or rqe_count {}",
+                self.max_msg_size, self.rqe_count
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the recv buffer of one side and its limits
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BufferInfo {
+    pub mr: RemoteMR,
+    pub limits: Limits,
+}
+
+impl BufferInfo {
+    pub fn validate(&self) -> Result<()> {
+        self.limits.validate()?;
+        if self.mr.length as u64 != self.limits.recv_buffer_size {
+            return Err(Error::Protocol(format!(
+                "recv buffer of {} bytes, {} advertised",
+                self.mr.length, self.limits.recv_buffer_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the limits both sides settled on, seen from this side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    // the largest message this side can send
+    pub max_msg_size: u32,
+    // writes in flight, bounded by the RQEs of the remote side
+    pub max_sending: u32,
+    // RQEs this side keeps posted
+    pub rqe_count: u32,
+    pub max_inline: u32,
+    pub send_buffer_size: u64,
+    // the features both sides support
+    pub features: u64,
+}
+
+impl Negotiated {
+    // max_send_wr is the send queue depth of the local QP
+    pub fn settle(local: &Limits, remote: &Limits, max_send_wr: u32, features: u64) -> Self {
+        // a message must leave room in either ring, or wrapping around never finds space
+        let max_msg_size = local
+            .max_msg_size
+            .min(remote.max_msg_size)
+            .min((local.send_buffer_size / 2).min(u32::MAX as u64) as u32)
+            .min((remote.recv_buffer_size / 2).min(u32::MAX as u64) as u32);
+        Self {
+            max_msg_size,
+            max_sending: remote.rqe_count.min(max_send_wr),
+            rqe_count: local.rqe_count,
+            max_inline: local.max_inline,
+            send_buffer_size: local.send_buffer_size,
+            features,
+        }
+    }
+}
+
+pub async fn write_frame<T: Serialize>(qp: &QP, kind: Kind, msg: &T) -> Result<()> {
+    qp.bootstrap()?.send(&encode_frame(kind, msg)?).await
+}
+
+// read a frame of `kind`, anything else is rejected before the payload is decoded
+pub async fn read_frame<T: DeserializeOwned>(qp: &QP, kind: Kind) -> Result<T> {
+    decode_frame(kind, &qp.bootstrap()?.recv().await?)
+}
+
+pub(crate) fn encode_frame<T: Serialize>(kind: Kind, msg: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(msg)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC.to_be_bytes());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.extend_from_slice(&(kind as u16).to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+pub(crate) fn decode_frame<T: DeserializeOwned>(kind: Kind, frame: &[u8]) -> Result<T> {
+    let length = frame_len(frame)?;
+    let got = u16::from_be_bytes([frame[6], frame[7]]);
+    if got != kind as u16 {
+        return Err(Error::Protocol(format!(
+            "expect message {:?}, got kind {}",
+            kind, got
+        )));
+    }
+    if frame.len() != HEADER_LEN + length {
+        return Err(Error::Protocol(format!(
+            "frame of {} bytes, {} in the header",
+            frame.len() - HEADER_LEN,
+            length
+        )));
+    }
+    Ok(bincode::deserialize(&frame[HEADER_LEN..])?)
+}
+
+// check the header of a frame, return the length of its payload
+pub(crate) fn frame_len(header: &[u8]) -> Result<usize> {
+    if header.len() < HEADER_LEN {
+        return Err(Error::Protocol(format!(
+            "frame of {} bytes is too short",
+            header.len()
+        )));
+    }
+    let magic = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    if magic != MAGIC {
+        return Err(Error::Protocol(format!(
+            "bad magic {:#010x}, the remote side is not an ibv peer",
+            magic
+        )));
+    }
+    if version != PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!(
+            "remote side speaks protocol version {}, we speak {}",
+            version, PROTOCOL_VERSION
+        )));
+    }
+    if length > MAX_FRAME_LEN {
+        return Err(Error::Protocol(format!(
+            "frame of {} bytes is too long",
+            length
+        )));
+    }
+    Ok(length as usize)
+}
+
+// exchanged by both sides of a recovering Conn
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resync {
+    // with a new PSN
+    pub endpoint: EndPoint,
+    // number of messages this side has received
+    pub delivered: u64,
+    // the position of the recv buffer released so far
+    pub released: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(buffer_size: u64, max_msg_size: u32, rqe_count: u32) -> Limits {
+        Limits {
+            recv_buffer_size: buffer_size,
+            send_buffer_size: buffer_size,
+            max_msg_size,
+            rqe_count,
+            max_inline: 0,
+        }
+    }
+
+    fn assert_protocol<T: std::fmt::Debug>(res: Result<T>, expect: &str) {
+        match res {
+            Err(Error::Protocol(msg)) => assert!(msg.contains(expect), "{}", msg),
+            other => panic!("expect a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let sent = limits(1024, 512, 16);
+        let frame = encode_frame(Kind::Buffer, &sent).unwrap();
+        assert_eq!(frame_len(&frame).unwrap(), frame.len() - HEADER_LEN);
+        let got: Limits = decode_frame(Kind::Buffer, &frame).unwrap();
+        assert_eq!(got, sent);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        frame[0] ^= 0xff;
+        assert_protocol(frame_len(&frame), "bad magic");
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        frame[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        assert_protocol(frame_len(&frame), "protocol version");
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        frame[8..12].copy_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert_protocol(frame_len(&frame), "too long");
+    }
+
+    #[test]
+    fn short_header() {
+        let frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        assert_protocol(frame_len(&frame[..HEADER_LEN - 1]), "too short");
+    }
+
+    #[test]
+    fn wrong_kind() {
+        let frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        assert_protocol(
+            decode_frame::<Limits>(Kind::Resync, &frame),
+            "expect message",
+        );
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let mut frame = encode_frame(Kind::Buffer, &Limits::default()).unwrap();
+        frame.push(0);
+        assert_protocol(
+            decode_frame::<Limits>(Kind::Buffer, &frame),
+            "in the header",
+        );
+        frame.truncate(frame.len() - 2);
+        assert_protocol(
+            decode_frame::<Limits>(Kind::Buffer, &frame),
+            "in the header",
+        );
+    }
+
+    #[test]
+    fn settle_takes_the_smaller_limits() {
+        let local = limits(1 << 20, 64 * 1024, 128);
+        let remote = limits(1 << 20, 32 * 1024, 256);
+        let settled = Negotiated::settle(&local, &remote, 64, features::ALL);
+        assert_eq!(settled.max_msg_size, 32 * 1024);
+        // bounded by the send queue of this side before the RQEs of the remote side
+        assert_eq!(settled.max_sending, 64);
+        assert_eq!(settled.rqe_count, 128);
+        assert_eq!(settled.send_buffer_size, 1 << 20);
+        assert_eq!(settled.features, features::ALL);
+
+        let settled = Negotiated::settle(&local, &remote, 1024, 0);
+        assert_eq!(settled.max_sending, 256);
+        assert_eq!(settled.features, 0);
+    }
+
+    #[test]
+    fn settle_leaves_room_in_both_rings() {
+        // half of the smaller ring, whichever side it is on
+        let local = limits(4096, u32::MAX, 16);
+        let mut remote = limits(1 << 20, u32::MAX, 16);
+        assert_eq!(
+            Negotiated::settle(&local, &remote, 16, 0).max_msg_size,
+            2048
+        );
+        remote.recv_buffer_size = 1024;
+        assert_eq!(Negotiated::settle(&local, &remote, 16, 0).max_msg_size, 512);
+    }
+
+    #[test]
+    fn limits_validate() {
+        assert!(Limits::default().validate().is_ok());
+        assert_protocol(limits(0, 1, 1).validate(), "buffer sizes");
+        assert_protocol(limits(1, 0, 1).validate(), "max_msg_size");
+        assert_protocol(limits(1, 1, 0).validate(), "rqe_count");
+    }
+}
@@ -1,6 +1,10 @@
+pub mod bootstrap;
+pub mod completion;
+pub mod context;
 pub mod cq;
 pub mod default;
 pub mod device;
+pub mod handshake;
 pub mod mr;
 pub mod pd;
 pub mod qp;
@@ -1,31 +1,40 @@
 extern crate bincode;
-use super::default::{DEFAULT_SEND_BUFFER_SIZE, MIN_LENGTH_TO_NOTIFY_RELEASE};
+use super::completion::{wait_until, Completion};
+use super::context::Context;
+use super::default::MIN_LENGTH_TO_NOTIFY_RELEASE;
 use super::pd::PD;
-use crate::connection::conn::{MyReceiver, MAX_SENDING};
+use crate::error::{Error, Result};
 use clippy_utilities::Cast;
 use rdma_sys::{ibv_access_flags, ibv_dereg_mr, ibv_mr, ibv_reg_mr, ibv_sge};
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::{ptr::NonNull, sync::Arc};
-use tokio::io;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard};
+use std::{io, ptr::NonNull, sync::Arc};
+use tokio::sync::mpsc::{Permit, Receiver, Sender, UnboundedReceiver};
+use tokio::sync::{Mutex, MutexGuard, Notify};
 use tokio::task::JoinHandle;
 
-#[derive(Clone)]
+// deregistered once on drop, share it with an Arc
 pub struct MR {
     inner: NonNull<ibv_mr>,
     pub addr: u64,
     pub length: u32,
     pub lkey: u32,
     pub rkey: u32,
+    // the PD is deallocated after its last MR
+    _pd: Arc<PD>,
+    // freed after drop has deregistered it, the HCA never touches freed memory
+    data: Box<[u8]>,
 }
 
 unsafe impl Send for MR {}
 unsafe impl Sync for MR {}
 
 impl MR {
-    pub fn new(pd: &PD, data: &mut [u8]) -> Self {
+    // the MR owns the memory it registers
+    pub fn new(pd: &Arc<PD>, data: Vec<u8>) -> Result<Self> {
+        let mut data = data.into_boxed_slice();
         // todo: access control
         let access = (ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
             | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
@@ -35,15 +44,29 @@ impl MR {
             .cast();
         // the code below will cause a segfault, because it copy the ibv_mr into a new memory in a temporary variable.
         // &mut unsafe { *ibv_reg_mr(pd.inner(), data.as_mut_ptr().cast(), data.len(), access) };
-        let mr =
-            unsafe { &mut *ibv_reg_mr(pd.inner(), data.as_mut_ptr().cast(), data.len(), access) };
-        Self {
-            inner: NonNull::new(mr).unwrap(),
+        let inner = NonNull::new(unsafe {
+            ibv_reg_mr(pd.inner(), data.as_mut_ptr().cast(), data.len(), access)
+        })
+        .ok_or_else(|| Error::RegMr(io::Error::last_os_error()))?;
+        let mr = unsafe { inner.as_ref() };
+        Ok(Self {
+            inner,
             addr: mr.addr as u64,
             length: mr.length.cast(),
             lkey: mr.lkey,
             rkey: mr.rkey,
-        }
+            _pd: pd.clone(),
+            data,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    // the remote side may write into a MR with its rkey at any time
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
     }
 
     pub fn inner(&self) -> *mut ibv_mr {
@@ -57,9 +80,13 @@ impl MR {
             lkey: self.lkey,
         }
     }
+}
 
-    pub fn dereg(&self) -> i32 {
-        unsafe { ibv_dereg_mr(self.inner()) }
+impl Drop for MR {
+    fn drop(&mut self) {
+        unsafe {
+            ibv_dereg_mr(self.inner());
+        }
     }
 }
 
@@ -73,7 +100,7 @@ pub struct RemoteMR {
 
 impl RemoteMR {
     // get MR form ibv_mr
-    pub fn from_mr(mr: Arc<MR>) -> Self {
+    pub fn from_mr(mr: &MR) -> Self {
         Self {
             addr: mr.addr as u64,
             length: mr.length as u32,
@@ -82,13 +109,13 @@ impl RemoteMR {
     }
 
     //serialize MR to Vec<u8>
-    pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
     }
 
     //deserialize Vec<u8> to MR
-    pub fn deserialize(data: Vec<u8>) -> Self {
-        bincode::deserialize(&data).unwrap()
+    pub fn deserialize(data: Vec<u8>) -> Result<Self> {
+        Ok(bincode::deserialize(&data)?)
     }
 }
 
@@ -120,8 +147,8 @@ impl Into<ibv_sge> for LocalBuf {
     }
 }
 
-impl From<Arc<MR>> for LocalBuf {
-    fn from(mr: Arc<MR>) -> Self {
+impl From<&MR> for LocalBuf {
+    fn from(mr: &MR) -> Self {
         Self {
             addr: mr.addr,
             length: mr.length,
@@ -138,261 +165,515 @@ pub struct RemoteBufManager {
     left: u64,
     right: u64,
     mr: RemoteMR,
+    // the index before each of the last allocations, by sequence number,
+    // to rewind the allocations that never reached the remote side.
+    history: StdMutex<VecDeque<(u64, u64)>>,
+    // number of allocations
+    seq: AtomicU64,
+    // the writes in flight at most, the history keeps as many
+    max_sending: usize,
+    // woken when done moves, the allocations waiting for space check again
+    released: Notify,
 }
 
 impl RemoteBufManager {
-    pub fn new(mr: RemoteMR) -> Self {
+    // max_sending is the negotiated bound of the writes in flight
+    pub fn new(mr: RemoteMR, max_sending: u32) -> Self {
         Self {
             done: AtomicU64::new(mr.addr),
             index: AtomicU64::new(mr.addr),
             left: mr.addr,
             right: mr.addr + mr.length as u64,
             mr,
+            history: StdMutex::new(VecDeque::new()),
+            seq: AtomicU64::new(0),
+            max_sending: max_sending.max(1) as usize,
+            released: Notify::new(),
         }
     }
 
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    // forget the allocations from `delivered` on, the remote side has received only
+    // the first `delivered` ones. return the number of allocations dropped.
+    pub fn rewind(&self, delivered: u64) -> Result<u64> {
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = self.seq.load(Ordering::Acquire);
+        if delivered >= seq {
+            return Ok(0);
+        }
+        let index = history
+            .iter()
+            .find(|(s, _)| *s == delivered)
+            .map(|(_, index)| *index)
+            .ok_or_else(|| {
+                Error::Recovery(format!(
+                    "can't rewind to message {}, only the last {} are kept",
+                    delivered,
+                    history.len()
+                ))
+            })?;
+        history.retain(|(s, _)| *s < delivered);
+        self.index.store(index, Ordering::Release);
+        self.seq.store(delivered, Ordering::Release);
+        Ok(seq - delivered)
+    }
+
+    // give back the last allocation, its write was never posted
+    pub fn cancel_last(&self) {
+        let seq = self.seq();
+        if seq > 0 {
+            // the entry was just pushed, so it is kept
+            let _ = self.rewind(seq - 1);
+        }
+    }
+
+    // the remote side tells how far it has released
+    pub fn reset_done(&self, done: u64) {
+        self.done.store(done, Ordering::Release);
+        self.released.notify_waiters();
+    }
+
     pub async fn alloc(&self, length: u32) -> RemoteBuf {
         let rkey = self.mr.rkey;
+        let before = self.index.load(Ordering::Acquire);
         let index = &self.index;
         let done = &self.done;
         // notice: index could catch up done, that is a constraint.
         if index.load(Ordering::Relaxed) + length as u64 > self.right {
             // wait for remote to release the space, until done > self.left + length as u64 (that means space is enough)
-            while self.left + length as u64 >= done.load(Ordering::Acquire)
-                || done.load(Ordering::Acquire) > index.load(Ordering::Relaxed)
-            {
-                // task sleep for a while
-                tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
-            }
+            wait_until(&self.released, || {
+                let done = done.load(Ordering::Acquire);
+                self.left + (length as u64) < done && done <= index.load(Ordering::Relaxed)
+            })
+            .await;
             index.store(self.left, Ordering::Release);
         } else {
             // wait for remote to release the space, until done > index + length as u64 (that means space is enough)
-            while index.load(Ordering::Relaxed) + length as u64 >= done.load(Ordering::Acquire)
-                && done.load(Ordering::Acquire) > index.load(Ordering::Relaxed)
-            {
-                // task sleep for a while
-                tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
-            }
+            wait_until(&self.released, || {
+                let done = done.load(Ordering::Acquire);
+                let index = index.load(Ordering::Relaxed);
+                index + (length as u64) < done || done <= index
+            })
+            .await;
         }
         let addr = index.fetch_add(length as u64, Ordering::Relaxed);
+        {
+            let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+            // the writes older than max_sending have been delivered
+            if history.len() >= self.max_sending {
+                history.pop_front();
+            }
+            history.push_back((self.seq.fetch_add(1, Ordering::AcqRel), before));
+        }
         RemoteBuf { addr, length, rkey }
     }
 
-    pub fn update(&self, length: u32) {
-        if self.done.load(Ordering::Relaxed) + length as u64 > self.right {
-            self.done
-                .store(self.left + length as u64, Ordering::Release);
-        } else {
-            self.done.fetch_add(length as u64, Ordering::Relaxed);
-        }
+    // the remote side has released its ring up to the position carried by imm,
+    // see RecvBuffer::release
+    pub fn update(&self, imm: u32) {
+        self.done
+            .store(self.left + imm as u64 - 1, Ordering::Release);
+        self.released.notify_waiters();
     }
 }
 
 // use BufPool instead
 pub struct SendBuffer {
-    mr: Arc<MR>,
-    _send_buf: Vec<u8>,
+    // owns the ring, deregistered before it is freed
+    mr: MR,
     done: Arc<AtomicU64>,
     index: Mutex<u64>,
     left: u64,
     right: u64,
     to_release: Arc<MyQueue>,
-    _release_task: JoinHandle<()>,
+    // woken by the release task when done moves
+    released: Arc<Notify>,
+    release_task: JoinHandle<()>,
 }
 
 impl SendBuffer {
-    pub async fn new(pd: &PD) -> Self {
-        let mut send_buf = vec![0u8; DEFAULT_SEND_BUFFER_SIZE];
-        let mr = Arc::new(MR::new(pd, &mut send_buf));
-        let local_buf = LocalBuf::from(mr.clone());
+    // max_sending is the negotiated bound of the writes in flight
+    pub async fn new(ctx: &Context, size: usize, max_sending: u32) -> Result<Self> {
+        let mr = ctx.register(vec![0u8; size])?;
+        let local_buf = LocalBuf::from(&mr);
         let done = Arc::new(AtomicU64::new(local_buf.addr));
         let index = Mutex::new(local_buf.addr);
         let left = local_buf.addr;
         let right = local_buf.addr + local_buf.length as u64;
-        let (tx, rx) = tokio::sync::mpsc::channel((2 * MAX_SENDING) as usize);
+        let (tx, rx) = tokio::sync::mpsc::channel(2 * max_sending.max(1) as usize);
         let to_release = Arc::new(MyQueue::new(tx, rx));
+        let released = Arc::new(Notify::new());
         let done_clone = done.clone();
         let to_release_clone = to_release.clone();
+        let released_clone = released.clone();
         let release_task = tokio::spawn(async move {
             loop {
                 // Receive the signal in order, only after the first rx receives the signal, the next one can receive it, and release the done in order
-                let (using, length) = to_release_clone.pop().await;
-                while using.load(Ordering::Relaxed) == true {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-                }
+                let (using, length) = match to_release_clone.pop().await {
+                    Ok(item) => item,
+                    Err(_) => break,
+                };
+                // the daemon completes it when the write completes or is flushed
+                using.wait().await;
                 if done_clone.load(Ordering::Relaxed) + length as u64 > right {
                     done_clone.store(left + length as u64, Ordering::Release);
                 } else {
-                    done_clone.fetch_add(length as u64, Ordering::Relaxed);
+                    done_clone.fetch_add(length as u64, Ordering::Release);
                 }
+                released_clone.notify_waiters();
             }
         });
-        Self {
-            _send_buf: send_buf,
+        Ok(Self {
             mr,
             done,
             index,
             left,
             right,
             to_release,
-            _release_task: release_task,
-        }
+            released,
+            release_task,
+        })
     }
 
-    pub async fn alloc(&self, length: u32) -> (LocalBuf, u64) {
+    // cancel safe: nothing is allocated until the last await, the caller owns the
+    // Completion behind the returned wr_id and must complete it if it never posts.
+    pub async fn alloc(&self, length: u32) -> Result<(LocalBuf, u64)> {
         let lkey = self.mr.lkey;
         let mut index = self.index.lock().await;
         let done = self.done.clone();
         // notice: index could catch up done, that is a constraint.
         if *index + length as u64 > self.right {
             // wait for remote to release the space, until done > self.left + length as u64 (that means space is enough)
-            while self.left + length as u64 >= done.load(Ordering::Acquire)
-                || done.load(Ordering::Acquire) > *index
-            {
-                // task sleep for a while
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-            }
+            wait_until(&self.released, || {
+                let done = done.load(Ordering::Acquire);
+                self.left + (length as u64) < done && done <= *index
+            })
+            .await;
             *index = self.left;
         } else {
             // wait for remote to release the space, until done > index + length as u64 (that means space is enough)
-            while *index + length as u64 >= done.load(Ordering::Acquire)
-                && done.load(Ordering::Acquire) > *index
-            {
-                // task sleep for a while
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-            }
+            wait_until(&self.released, || {
+                let done = done.load(Ordering::Acquire);
+                *index + (length as u64) < done || done <= *index
+            })
+            .await;
         }
+        // the release task keeps up with the writes, so this rarely waits
+        let permit = self.to_release.reserve().await?;
         let addr = *index;
         *index += length as u64;
-        (
-            LocalBuf { addr, length, lkey },
-            self.add_to_release(length).await,
-        )
-    }
-
-    pub async fn add_to_release(&self, length: u32) -> u64 {
-        let using = Arc::new(AtomicBool::new(true));
-        let using_clone = using.clone();
-        self.to_release.push(using, length).await;
-
-        Arc::into_raw(using_clone) as u64
+        let using = Completion::new();
+        let wr_id = using.wr_id();
+        permit.send((using, length));
+        Ok((LocalBuf { addr, length, lkey }, wr_id))
     }
 }
 
 impl Drop for SendBuffer {
     fn drop(&mut self) {
-        self.mr.dereg();
+        self.release_task.abort();
     }
 }
 
 pub struct RecvBuffer {
-    mr: Arc<MR>,
-    // from polling
-    pub rx: *mut Receiver<(u32, u32)>,
-    recv_buffer: Vec<u8>,
-    // it the length of gathered buf to release
-    released: *mut u32,
-    // it the position of the buf have been notify to release
-    done: *mut u64,
-    index: *mut u64,
+    // owns the ring, deregistered before it is freed
+    mr: MR,
+    // the length and imm of each message from the daemon. concurrent receivers
+    // hold it across recv and read, so the messages are read in arrival order
+    rx: Mutex<UnboundedReceiver<(u32, u32)>>,
+    ring: StdMutex<Ring>,
     left: u64,
-    right: u64,
 }
 
-unsafe impl Send for RecvBuffer {}
-unsafe impl Sync for RecvBuffer {}
+// the messages handed out by read, they may be released in any order but the
+// space is freed in order
+struct Ring {
+    left: u64,
+    right: u64,
+    // released bytes are notified to the remote side once they add up to this
+    threshold: u32,
+    // the position of the next message
+    index: u64,
+    // the position the remote side has been told is released
+    done: u64,
+    // the end of the messages released in order, not told yet
+    released: u64,
+    // bytes released after done
+    pending: u64,
+    // the seq of the first outstanding message
+    first: u64,
+    // the end of each outstanding message and whether it is released
+    outstanding: VecDeque<(u64, bool)>,
+}
 
 impl RecvBuffer {
-    pub fn new(mr: Arc<MR>, recv_buffer: Vec<u8>, rx: Receiver<(u32, u32)>) -> Self {
-        Self {
-            mr: mr.clone(),
-            rx: Box::into_raw(Box::new(rx)),
-            recv_buffer,
-            released: Box::into_raw(Box::new(0)),
-            done: Box::into_raw(Box::new(mr.addr)),
-            index: Box::into_raw(Box::new(mr.addr)),
+    pub fn new(ctx: &Context, size: usize, rx: UnboundedReceiver<(u32, u32)>) -> Result<Self> {
+        let mr = ctx.register(vec![0u8; size])?;
+        Ok(Self {
+            rx: Mutex::new(rx),
+            ring: StdMutex::new(Ring::new(
+                mr.addr,
+                mr.addr + mr.length as u64,
+                MIN_LENGTH_TO_NOTIFY_RELEASE,
+            )),
             left: mr.addr,
-            right: mr.addr + mr.length as u64,
+            mr,
+        })
+    }
+
+    pub fn set_release_threshold(&mut self, release_threshold: u32) {
+        self.ring
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .threshold = release_threshold;
+    }
+
+    pub fn mr(&self) -> &MR {
+        &self.mr
+    }
+
+    fn ring(&self) -> StdMutexGuard<'_, Ring> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // hand out the next message, return its seq to release it with
+    pub fn read(&self, length: u32) -> Result<(u64, &[u8])> {
+        let (seq, start, end) = self.ring().read(length);
+        let buf = &self.mr.as_slice()[(start - self.left) as usize..(end - self.left) as usize];
+        Ok((seq, buf))
+    }
+
+    // the queue of received messages, read them before letting it go
+    pub async fn queue(&self) -> RecvQueue<'_> {
+        RecvQueue(self.rx.lock().await)
+    }
+
+    // the position the remote side has been told is released
+    pub fn released_position(&self) -> u64 {
+        self.ring().done
+    }
+
+    // the message seq has been handled. the space is freed only up to the first
+    // message still held, return the imm telling the remote side the new released
+    // position once enough has been freed. the imm is the offset in the ring plus
+    // one, 0 means nothing released.
+    pub fn release(&self, seq: u64) -> Option<u32> {
+        self.ring().release(seq)
+    }
+}
+
+impl Ring {
+    fn new(left: u64, right: u64, threshold: u32) -> Self {
+        Self {
+            left,
+            right,
+            threshold,
+            index: left,
+            done: left,
+            released: left,
+            pending: 0,
+            first: 0,
+            outstanding: VecDeque::new(),
         }
     }
 
-    // after recv data form the &[u8], need to call release_buf to release the buf
-    pub fn read(&self, length: u32) -> io::Result<&[u8]> {
-        // get slice form recv_buffer
-        let index = unsafe { &mut *self.index };
-        let mut start = *index;
+    // the seq, start and end of the next message
+    fn read(&mut self, length: u32) -> (u64, u64, u64) {
+        let mut start = self.index;
         let mut end = start + length as u64;
-        // right check
+        // the remote side wraps around when the message doesn't fit before right
         if end > self.right {
             start = self.left;
             end = self.left + length as u64;
         }
-        *index = end;
-        let buf = &self.recv_buffer[(start - self.mr.addr) as usize..(end - self.mr.addr) as usize];
-        Ok(buf)
-    }
-
-    pub fn rx(&self) -> &mut Receiver<(u32, u32)> {
-        unsafe { &mut *(self.rx) }
-    }
-
-    pub async fn recv(&self) -> (u32, u32) {
-        self.rx().recv().await.unwrap()
-    }
-
-    pub fn notify_release(&self, length: u32) -> Option<u32> {
-        let released = unsafe { &mut *self.released };
-        let done = unsafe { &mut *self.done };
-        // if over self.right, reset done to self.left.
-        if *done + *released as u64 + length as u64 > self.right {
-            let ret = *released;
-            // length may be over MIN_LENGTH_TO_NOTIFY_RELEASE
-            *released = length;
-            *done = self.left;
-            return Some(ret);
-        } else if *released + length >= MIN_LENGTH_TO_NOTIFY_RELEASE {
-            let ret = *released + length;
-            *done += ret as u64;
-            *released = 0;
-            return Some(ret);
-        } else {
-            *released += length;
+        self.index = end;
+        self.outstanding.push_back((end, false));
+        let seq = self.first + self.outstanding.len() as u64 - 1;
+        (seq, start, end)
+    }
+
+    fn release(&mut self, seq: u64) -> Option<u32> {
+        let slot = seq
+            .checked_sub(self.first)
+            .and_then(|i| self.outstanding.get_mut(i as usize))?;
+        if slot.1 {
+            // released twice
+            return None;
+        }
+        slot.1 = true;
+        while let Some(&(end, true)) = self.outstanding.front() {
+            // a wrapped message starts at left, the tail before it is free too
+            let start = if end < self.released {
+                self.left
+            } else {
+                self.released
+            };
+            self.pending += end - start;
+            self.released = end;
+            self.outstanding.pop_front();
+            self.first += 1;
+        }
+        if self.pending < self.threshold as u64 || self.released == self.done {
             return None;
         }
+        self.done = self.released;
+        self.pending = 0;
+        Some((self.done - self.left + 1) as u32)
     }
 }
 
-impl Drop for RecvBuffer {
-    fn drop(&mut self) {
-        unsafe {
-            let _ = Box::from_raw(self.rx);
-            let _ = Box::from_raw(self.released);
-            let _ = Box::from_raw(self.done);
-            let _ = Box::from_raw(self.index);
-            self.mr.dereg();
-        }
+pub struct RecvQueue<'a>(MutexGuard<'a, UnboundedReceiver<(u32, u32)>>);
+
+impl RecvQueue<'_> {
+    // the length and imm of the next message
+    pub async fn recv(&mut self) -> Result<(u32, u32)> {
+        self.0.recv().await.ok_or(Error::Disconnected)
     }
 }
 
+// popped by the release task only, the lock is never contended
 pub struct MyQueue(
-    Sender<(Arc<AtomicBool>, u32)>,
-    MyReceiver<(Arc<AtomicBool>, u32)>,
+    Sender<(Arc<Completion>, u32)>,
+    Mutex<Receiver<(Arc<Completion>, u32)>>,
 );
 
-unsafe impl Send for MyQueue {}
-unsafe impl Sync for MyQueue {}
-
 impl MyQueue {
-    pub fn new(tx: Sender<(Arc<AtomicBool>, u32)>, rx: Receiver<(Arc<AtomicBool>, u32)>) -> Self {
-        let rx = MyReceiver::new(rx);
-        Self(tx, rx)
+    pub fn new(tx: Sender<(Arc<Completion>, u32)>, rx: Receiver<(Arc<Completion>, u32)>) -> Self {
+        Self(tx, Mutex::new(rx))
     }
 
-    pub async fn push(&self, flag: Arc<AtomicBool>, length: u32) {
-        self.0.send((flag, length)).await.unwrap();
+    // a slot to push into without waiting
+    pub async fn reserve(&self) -> Result<Permit<'_, (Arc<Completion>, u32)>> {
+        self.0.reserve().await.map_err(|_| Error::Disconnected)
     }
 
-    pub async fn pop(&self) -> (Arc<AtomicBool>, u32) {
-        self.1.recv().await
+    pub async fn pop(&self) -> Result<(Arc<Completion>, u32)> {
+        self.1.lock().await.recv().await.ok_or(Error::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEFT: u64 = 0x1000;
+
+    // a ring of size bytes that tells every release
+    fn ring(size: u64) -> Ring {
+        Ring::new(LEFT, LEFT + size, 1)
+    }
+
+    #[test]
+    fn release_in_order() {
+        let mut ring = ring(100);
+        assert_eq!(ring.read(10), (0, LEFT, LEFT + 10));
+        assert_eq!(ring.read(20), (1, LEFT + 10, LEFT + 30));
+        assert_eq!(ring.release(0), Some(11));
+        assert_eq!(ring.release(1), Some(31));
+        assert_eq!(ring.done, LEFT + 30);
+    }
+
+    #[test]
+    fn release_out_of_order() {
+        let mut ring = ring(100);
+        for length in [10, 20, 30] {
+            ring.read(length);
+        }
+        // the space is freed only up to the first message still held
+        assert_eq!(ring.release(2), None);
+        assert_eq!(ring.release(1), None);
+        assert_eq!(ring.done, LEFT);
+        assert_eq!(ring.release(0), Some(61));
+        assert!(ring.outstanding.is_empty());
+        assert_eq!(ring.first, 3);
+    }
+
+    #[test]
+    fn release_twice_or_unknown() {
+        let mut ring = ring(100);
+        ring.read(10);
+        ring.read(10);
+        assert_eq!(ring.release(1), None);
+        assert_eq!(ring.release(1), None);
+        assert_eq!(ring.release(2), None);
+        assert_eq!(ring.release(0), Some(21));
+        // released and popped
+        assert_eq!(ring.release(0), None);
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut ring = ring(100);
+        assert_eq!(ring.read(60), (0, LEFT, LEFT + 60));
+        assert_eq!(ring.read(30), (1, LEFT + 60, LEFT + 90));
+        // doesn't fit before right, the remote side wrote it at left
+        assert_eq!(ring.read(20), (2, LEFT, LEFT + 20));
+        assert_eq!(ring.release(0), Some(61));
+        assert_eq!(ring.release(1), Some(91));
+        assert_eq!(ring.release(2), Some(21));
+        assert_eq!(ring.pending, 0);
+        assert_eq!(ring.done, LEFT + 20);
+    }
+
+    #[test]
+    fn wraparound_out_of_order() {
+        let mut ring = Ring::new(LEFT, LEFT + 100, 40);
+        ring.read(60);
+        ring.read(30);
+        ring.read(20);
+        assert_eq!(ring.release(2), None);
+        assert_eq!(ring.release(1), None);
+        // the wrapped message frees the tail after 90 too
+        assert_eq!(ring.release(0), Some(21));
+    }
+
+    #[test]
+    fn threshold() {
+        let mut ring = Ring::new(LEFT, LEFT + 100, 25);
+        ring.read(10);
+        ring.read(10);
+        ring.read(10);
+        assert_eq!(ring.release(0), None);
+        assert_eq!(ring.release(1), None);
+        assert_eq!(ring.release(2), Some(31));
+        assert_eq!(ring.pending, 0);
+    }
+
+    #[test]
+    fn imm_is_offset_plus_one() {
+        let mut ring = ring(100);
+        // nothing freed, nothing told: 0 never carries a position
+        ring.read(0);
+        assert_eq!(ring.release(0), None);
+        // a message ending at right
+        ring.read(100);
+        assert_eq!(ring.release(1), Some(101));
+    }
+
+    #[test]
+    fn imm_round_trip() {
+        let mut ring = ring(100);
+        let remote = RemoteBufManager::new(
+            RemoteMR {
+                addr: LEFT,
+                length: 100,
+                rkey: 0,
+            },
+            16,
+        );
+        ring.read(60);
+        ring.read(30);
+        ring.read(20);
+        for seq in [1, 0, 2] {
+            if let Some(imm) = ring.release(seq) {
+                assert_ne!(imm, 0);
+                remote.update(imm);
+                assert_eq!(remote.done.load(Ordering::Acquire), ring.done);
+            }
+        }
+        assert_eq!(remote.done.load(Ordering::Acquire), LEFT + 20);
     }
 }
@@ -1,6 +1,7 @@
 use super::device::Device;
+use crate::error::{Error, Result};
 use rdma_sys::*;
-use std::{ptr::NonNull, sync::Arc};
+use std::{io, ptr::NonNull, sync::Arc};
 
 pub struct PD {
     inner: NonNull<ibv_pd>,
@@ -11,11 +12,11 @@ unsafe impl Send for PD {}
 unsafe impl Sync for PD {}
 
 impl PD {
-    pub fn new(device: Arc<Device>) -> Self {
-        Self {
-            inner: alloc_pd(&device),
+    pub fn new(device: Arc<Device>) -> Result<Self> {
+        Ok(Self {
+            inner: alloc_pd(&device)?,
             device,
-        }
+        })
     }
 
     pub fn inner(&self) -> *mut ibv_pd {
@@ -35,7 +36,7 @@ impl Drop for PD {
     }
 }
 
-pub fn alloc_pd(device: &Device) -> NonNull<ibv_pd> {
+pub fn alloc_pd(device: &Device) -> Result<NonNull<ibv_pd>> {
     let pd = unsafe { ibv_alloc_pd(device.inner()) };
-    NonNull::new(pd).unwrap()
+    NonNull::new(pd).ok_or_else(|| Error::AllocPd(io::Error::last_os_error()))
 }
@@ -1,20 +1,18 @@
-use log::error;
-use rdma_sys::ibv_qp_state::{IBV_QPS_ERR, IBV_QPS_INIT, IBV_QPS_RTR, IBV_QPS_RTS};
+use rdma_sys::ibv_qp_state::{
+    IBV_QPS_ERR, IBV_QPS_INIT, IBV_QPS_RESET, IBV_QPS_RTR, IBV_QPS_RTS, IBV_QPS_SQD, IBV_QPS_SQE,
+};
 use std::{
     fmt::{self, Debug, Formatter},
-    io::{Error, Result},
-    mem::{self, size_of},
+    io, mem,
     ptr::{self, NonNull},
-    sync::Arc,
-};
-use tokio::sync::{
-    mpsc::{self, Sender},
-    Mutex,
-};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
 
 extern crate bincode;
 use serde::{Deserialize, Serialize};
@@ -22,41 +20,94 @@ use serde::{Deserialize, Serialize};
 use clippy_utilities::Cast;
 use rdma_sys::*;
 
-use super::default::{DEFAULT_RECV_BUFFER_SIZE, DEFAULT_RQE_COUNT};
+use super::default::MAX_QP_WR;
 use super::{
+    bootstrap::{Bootstrap, TcpBootstrap},
+    completion::Completion,
+    context::Context,
     cq::CQ,
-    default::DEFAULT_GID_INDEX,
-    device::Device,
-    mr::{LocalBuf, RecvBuffer, RemoteBuf, RemoteMR, MR},
+    device::{Device, DeviceCaps, Mtu},
+    handshake::{read_frame, write_frame, BufferInfo, Hello, Kind, Limits, Negotiated},
+    mr::{LocalBuf, RecvBuffer, RemoteBuf, RemoteMR},
     pd::PD,
     wr::{RDMAType, WRType, RDMA, WR},
 };
+use crate::error::{Error, Result};
 
 pub struct QP {
     inner: NonNull<ibv_qp>,
-    pub pd: Arc<PD>,
+    // the device and the PD shared with the other QPs and buffers
+    pub ctx: Arc<Context>,
     pub cq: Arc<CQ>,
-    stream: Option<Mutex<TcpStream>>,
+    // the out-of-band channel of the handshake and recovery
+    bootstrap: Option<Box<dyn Bootstrap>>,
+    // validated against the device, the mtu is always set
+    config: QPConfig,
+    // the ibv_qp_state after the last transition, see query for the real one
+    state: AtomicU32,
+    // the first PSN this QP sends, told to the remote side in the endpoint
+    psn: AtomicU32,
+    // the features both sides support, known after the handshake
+    features: u64,
 }
 
-impl QP {
-    pub fn new(device: Arc<Device>, qp_cap: QPCap) -> Self {
-        let pd = Arc::new(PD::new(device.clone()));
-        let cq = Arc::new(CQ::new(device.clone(), false));
+// creates a QP after validating its QPConfig against the device
+pub struct QPBuilder {
+    ctx: Arc<Context>,
+    cq: Arc<CQ>,
+    config: QPConfig,
+}
+
+impl QPBuilder {
+    // the CQ may be shared with other QPs, the wcs carry the qp_num to tell them apart
+    pub fn new(ctx: Arc<Context>, cq: Arc<CQ>) -> Self {
         Self {
-            inner: create_qp(&pd, &cq, qp_cap),
-            pd,
+            ctx,
             cq,
-            stream: None,
+            config: QPConfig::default(),
         }
     }
 
+    pub fn config(mut self, config: QPConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<QP> {
+        let config = self.config.validate(self.ctx.device())?;
+        // the CQ may be shared, it must hold the wcs of every QP on it
+        self.cq.reserve(config.cap.wrs())?;
+        let inner = match create_qp(self.ctx.pd(), &self.cq, config.cap) {
+            Ok(inner) => inner,
+            Err(e) => {
+                self.cq.release(config.cap.wrs());
+                return Err(e);
+            }
+        };
+        Ok(QP {
+            inner,
+            ctx: self.ctx,
+            cq: self.cq,
+            bootstrap: None,
+            state: AtomicU32::new(IBV_QPS_RESET),
+            psn: AtomicU32::new(config.psn.unwrap_or_else(fresh_psn)),
+            features: 0,
+            config,
+        })
+    }
+}
+
+impl QP {
+    pub fn config(&self) -> &QPConfig {
+        &self.config
+    }
+
     pub fn inner(&self) -> *mut ibv_qp {
         self.inner.as_ptr()
     }
 
     pub fn pd(&self) -> *mut ibv_pd {
-        self.pd.inner()
+        self.ctx.pd().inner()
     }
 
     pub fn cq(&self) -> *mut ibv_cq {
@@ -64,59 +115,149 @@ impl QP {
     }
 
     pub fn set_stream(&mut self, stream: TcpStream) {
-        self.stream = Some(Mutex::new(stream));
+        self.set_bootstrap(Box::new(TcpBootstrap::new(stream)));
     }
 
-    pub async fn tcp_recv(&self, buf: &mut [u8]) -> Result<()> {
-        self.stream
-            .as_ref()
-            .unwrap()
-            .lock()
-            .await
-            .read_exact(buf)
-            .await?;
-        Ok(())
+    pub fn set_bootstrap(&mut self, bootstrap: Box<dyn Bootstrap>) {
+        self.bootstrap = Some(bootstrap);
     }
 
-    pub async fn tcp_send(&self, buf: &[u8]) -> Result<()> {
-        self.stream
-            .as_ref()
-            .unwrap()
-            .lock()
-            .await
-            .write_all(buf)
-            .await?;
-        Ok(())
+    pub(crate) fn bootstrap(&self) -> Result<&dyn Bootstrap> {
+        self.bootstrap.as_deref().ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "bootstrap channel of QP is not set",
+            ))
+        })
     }
 
     pub fn qpn(&self) -> u32 {
         unsafe { self.inner.as_ref().qp_num }
     }
 
+    // the state after the last transition made through this QP, or ERROR once a
+    // wc has failed or the device has reported a fatal async event for it. the
+    // hardware only moves a QP on its own to ERROR or SQE, use query for the real state.
     pub fn status(&self) -> Status {
-        let status = unsafe { self.inner.as_ref().state };
-        match status {
-            IBV_QPS_INIT => Status::INIT,
-            IBV_QPS_RTR => Status::RTR,
-            IBV_QPS_RTS => Status::RTS,
-            IBV_QPS_ERR => Status::ERROR,
-            _ => Status::Unknown,
+        Status::from(self.state.load(Ordering::Acquire))
+    }
+
+    // the current attributes and state of the QP as reported by the driver
+    pub fn query(&self) -> Result<QPAttr> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        let mut init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
+            | ibv_qp_attr_mask::IBV_QP_CUR_STATE
+            | ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
+            | ibv_qp_attr_mask::IBV_QP_PORT
+            | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS
+            | ibv_qp_attr_mask::IBV_QP_AV
+            | ibv_qp_attr_mask::IBV_QP_PATH_MTU
+            | ibv_qp_attr_mask::IBV_QP_DEST_QPN
+            | ibv_qp_attr_mask::IBV_QP_RQ_PSN
+            | ibv_qp_attr_mask::IBV_QP_SQ_PSN
+            | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
+            | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC
+            | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER
+            | ibv_qp_attr_mask::IBV_QP_TIMEOUT
+            | ibv_qp_attr_mask::IBV_QP_RETRY_CNT
+            | ibv_qp_attr_mask::IBV_QP_RNR_RETRY
+            | ibv_qp_attr_mask::IBV_QP_CAP;
+        if unsafe { ibv_query_qp(self.inner(), &mut attr, attr_mask.0.cast(), &mut init_attr) } != 0
+        {
+            return Err(Error::QueryQp(io::Error::last_os_error()));
+        }
+        self.state.store(attr.qp_state, Ordering::Release);
+        Ok(QPAttr { attr, init_attr })
+    }
+
+    // a failed wc or a fatal async event moves the QP to ERROR, no more WRs will be executed
+    pub(crate) fn set_error(&self) {
+        self.state.store(IBV_QPS_ERR, Ordering::Release);
+    }
+
+    // fail with InvalidQpState unless the QP is in one of the states. the cached
+    // state is right unless the hardware has moved the QP since, so the driver is
+    // asked before failing, e.g. after a failure whose wc or event isn't polled yet.
+    pub(crate) fn expect(&self, states: &[Status], op: &'static str) -> Result<()> {
+        if states.contains(&self.status()) {
+            return Ok(());
+        }
+        let state = self.query()?.state();
+        if states.contains(&state) {
+            Ok(())
+        } else {
+            Err(Error::InvalidQpState { state, op })
+        }
+    }
+
+    // move the QP from one of `from` to `to`
+    fn modify(
+        &self,
+        attr: &mut ibv_qp_attr,
+        attr_mask: ibv_qp_attr_mask,
+        from: &[Status],
+        to: Status,
+        op: &'static str,
+    ) -> Result<()> {
+        self.expect(from, op)?;
+        if unsafe { ibv_modify_qp(self.inner(), attr, attr_mask.0.cast()) } != 0 {
+            return Err(Error::ModifyQp {
+                state: to,
+                source: io::Error::last_os_error(),
+            });
         }
+        self.state.store(attr.qp_state, Ordering::Release);
+        Ok(())
+    }
+
+    // move the QP with attributes computed elsewhere, e.g. by rdma_init_qp_attr
+    pub(crate) fn transition(
+        &self,
+        attr: &mut ibv_qp_attr,
+        attr_mask: ibv_qp_attr_mask,
+    ) -> Result<()> {
+        let to = Status::from(attr.qp_state);
+        let from: &[Status] = match to {
+            Status::INIT => &[Status::RESET],
+            Status::RTR => &[Status::INIT],
+            Status::RTS => &[Status::RTR],
+            _ => &ANY_STATE,
+        };
+        self.modify(attr, attr_mask, from, to, "transition")
+    }
+
+    // when the features are settled without the handshake
+    pub(crate) fn set_features(&mut self, features: u64) {
+        self.features = features;
     }
 
     pub fn endpoint(&self) -> EndPoint {
         EndPoint {
-            lid: self.pd.device.lid(),
+            lid: self.ctx.device().lid(),
             qpn: self.qpn(),
-            gid: self.pd.device.gid(DEFAULT_GID_INDEX as i8),
+            gid: self.ctx.device().local_gid(),
+            psn: self.psn.load(Ordering::Acquire),
+            mtu: self.config.mtu.unwrap_or(Mtu::Mtu1024).bytes(),
         }
     }
 
+    // pick a new PSN for the next connection, the old packets in the fabric are
+    // then out of sequence for the remote side.
+    pub fn renew_psn(&self) {
+        self.psn.store(fresh_psn(), Ordering::Release);
+    }
+
+    // the features both sides support, see handshake::features
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
     pub fn init(&self) -> Result<()> {
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
         attr.pkey_index = 0;
-        attr.port_num = 1;
+        attr.port_num = self.ctx.device().port();
         attr.qp_access_flags = (ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
             | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
             | ibv_access_flags::IBV_ACCESS_REMOTE_READ)
@@ -125,56 +266,49 @@ impl QP {
             | ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
             | ibv_qp_attr_mask::IBV_QP_PORT
             | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
-        if unsafe { ibv_modify_qp(self.inner(), &mut attr, attr_mask.0.cast()) } != 0 {
-            return Err(Error::last_os_error());
-        }
-        Ok(())
+        self.modify(&mut attr, attr_mask, &[Status::RESET], Status::INIT, "init")
     }
 
-    pub async fn handshake(&mut self) {
-        // Exchange QP information withw the remote side
-        let enp = self.endpoint();
-        let bytes = enp.to_bytes();
-        if let Err(_) = self.tcp_send(bytes.as_slice()).await {
-            error!("send local endpoint error");
-        }
-        let mut buf = vec![0; bytes.len()];
-        if let Err(_) = self.tcp_recv(buf.as_mut_slice()).await {
-            error!("recv remote endpoint error");
-        }
-        let remote_enp = EndPoint::from_bytes(&buf);
-        if let Err(err) = self.ready_to_receive(remote_enp) {
-            error!("handshake RTR err: {}", err);
-        }
-        if let Err(err) = self.ready_to_send() {
-            error!("handshake RTS err: {}", err);
-        }
+    // exchange the endpoints, then move the QP to RTS. return what the remote side
+    // announced, the buffer sizes in `local` are only announced.
+    pub async fn handshake(&mut self, local: Hello) -> Result<Hello> {
+        write_frame(self, Kind::Hello, &local).await?;
+        let remote: Hello = read_frame(self, Kind::Hello).await?;
+        remote.validate()?;
+        self.features = local.features & remote.features;
+        self.ready_to_receive(remote.endpoint)?;
+        self.ready_to_send()?;
+        Ok(remote)
     }
 
     pub fn ready_to_receive(&self, remote_emp: EndPoint) -> Result<()> {
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.qp_state = ibv_qp_state::IBV_QPS_RTR;
-        attr.path_mtu = ibv_mtu::IBV_MTU_1024;
+        let config = &self.config;
+        // the path mtu is the smaller one of both sides
+        let mtu = config.mtu.unwrap_or(Mtu::Mtu1024);
+        let remote_mtu = Mtu::from_bytes(remote_emp.mtu).unwrap_or(Mtu::Mtu256);
+        attr.path_mtu = mtu.min(remote_mtu).to_ibv();
         attr.dest_qp_num = remote_emp.qpn;
         // qp_attr.rq_psn(X) must be equal to qp_attr.sq_psn(Y)
-        attr.rq_psn = 0;
-        attr.max_dest_rd_atomic = 1;
-        attr.min_rnr_timer = 18;
+        attr.rq_psn = remote_emp.psn;
+        attr.max_dest_rd_atomic = config.max_dest_rd_atomic;
+        attr.min_rnr_timer = config.min_rnr_timer;
         attr.ah_attr = ibv_ah_attr {
             dlid: remote_emp.lid.cast(),
-            sl: 0,
+            sl: config.sl,
             src_path_bits: 0,
             static_rate: 0,
             is_global: 1,
-            port_num: 1,
+            port_num: self.ctx.device().port(),
             grh: ibv_global_route {
-                sgid_index: DEFAULT_GID_INDEX as u8,
+                sgid_index: self.ctx.device().gid_index(),
                 dgid: ibv_gid {
                     raw: remote_emp.gid,
                 },
-                hop_limit: 255,
-                traffic_class: 0,
-                flow_label: 0,
+                hop_limit: config.hop_limit,
+                traffic_class: config.traffic_class,
+                flow_label: config.flow_label,
             },
         };
         let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
@@ -184,62 +318,164 @@ impl QP {
             | ibv_qp_attr_mask::IBV_QP_RQ_PSN
             | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
             | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
-        if unsafe { ibv_modify_qp(self.inner(), &mut attr, attr_mask.0.cast()) } != 0 {
-            return Err(Error::last_os_error());
-        }
-        Ok(())
+        self.modify(
+            &mut attr,
+            attr_mask,
+            &[Status::INIT],
+            Status::RTR,
+            "move to RTR",
+        )
     }
 
     pub fn ready_to_send(&self) -> Result<()> {
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.qp_state = ibv_qp_state::IBV_QPS_RTS;
-        attr.timeout = 14;
-        attr.retry_cnt = 6;
-        attr.rnr_retry = 6;
-        attr.sq_psn = 0;
-        attr.max_rd_atomic = 1;
+        attr.timeout = self.config.timeout;
+        attr.retry_cnt = self.config.retry_cnt;
+        attr.rnr_retry = self.config.rnr_retry;
+        attr.sq_psn = self.psn.load(Ordering::Acquire);
+        attr.max_rd_atomic = self.config.max_rd_atomic;
         let attr_mask = ibv_qp_attr_mask::IBV_QP_STATE
             | ibv_qp_attr_mask::IBV_QP_TIMEOUT
             | ibv_qp_attr_mask::IBV_QP_RETRY_CNT
             | ibv_qp_attr_mask::IBV_QP_RNR_RETRY
             | ibv_qp_attr_mask::IBV_QP_SQ_PSN
             | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
-        if unsafe { ibv_modify_qp(self.inner(), &mut attr, attr_mask.0.cast()) } != 0 {
-            return Err(Error::last_os_error());
-        }
-        Ok(())
+        self.modify(
+            &mut attr,
+            attr_mask,
+            &[Status::RTR],
+            Status::RTS,
+            "move to RTS",
+        )
     }
 
-    pub async fn exchange_recv_buf(&mut self) -> (RecvBuffer, RemoteMR, Sender<(u32, u32)>) {
-        let mut recv_buffer = vec![0u8; DEFAULT_RECV_BUFFER_SIZE];
-        let mr = Arc::new(MR::new(&self.pd, &mut recv_buffer));
-        let (tx, rx) = mpsc::channel(DEFAULT_RQE_COUNT as usize);
-        let recv_buffer = RecvBuffer::new(mr.clone(), recv_buffer, rx);
-        // send local_buf to remote
-        let send_mr = RemoteMR::from_mr(mr);
+    // move the QP back to RESET from any state, the posted WRs are dropped without wcs
+    pub fn reset(&self) -> Result<()> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_RESET;
+        self.modify(
+            &mut attr,
+            ibv_qp_attr_mask::IBV_QP_STATE,
+            &ANY_STATE,
+            Status::RESET,
+            "reset",
+        )
+    }
 
-        self.send_mr(send_mr).await;
-        // receive remote_buf from remote
-        let remote_mr = self.recv_mr().await;
+    // move the QP to ERROR and wait until every posted WR has been flushed, so no wc of
+    // this QP is left in the CQ. the CQ must be polled meanwhile, a CQ nobody polls
+    // fails the drain once timeout has passed.
+    pub async fn drain(&self, timeout: Option<Duration>) -> Result<()> {
+        let markers = self.flush()?;
+        let flushed = async {
+            for flushed in markers {
+                flushed.wait().await;
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, flushed).await.map_err(|_| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the flushed wcs weren't polled in time",
+                ))
+            }),
+            None => {
+                flushed.await;
+                Ok(())
+            }
+        }
+    }
 
-        // channel to notify recvbuf is ready
+    // move to ERROR and post a marker to each queue, the markers are cleared when
+    // their flushed wcs are polled.
+    pub(crate) fn flush(&self) -> Result<Vec<Arc<Completion>>> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_ERR;
+        self.modify(
+            &mut attr,
+            ibv_qp_attr_mask::IBV_QP_STATE,
+            &ANY_STATE,
+            Status::ERROR,
+            "drain",
+        )?;
+        // the queues are flushed in order, so once the last WR of each queue is
+        // flushed, so are the ones before it.
+        let mut markers = Vec::new();
+        for wr_type in [WRType::SEND, WRType::RECV] {
+            let flushed = Completion::new();
+            let wr_id = flushed.wr_id();
+            if let Err(e) = WR::new(wr_id, wr_type, vec![], None).post(self) {
+                unsafe { drop(Completion::from_wr_id(wr_id)) };
+                return Err(e);
+            }
+            markers.push(flushed);
+        }
+        Ok(markers)
+    }
 
-        (recv_buffer, remote_mr, tx)
+    // bring a QP that has been reset and re-initialized back to RTS
+    pub fn reconnect(&self, remote: EndPoint) -> Result<()> {
+        self.ready_to_receive(remote)?;
+        self.ready_to_send()
     }
 
-    pub async fn send_mr(&mut self, remote_mr: RemoteMR) {
-        let bytes = remote_mr.serialize();
-        self.tcp_send(bytes.as_slice()).await.unwrap();
+    // register the recv buffer advertised in `local`, send it with the limits of this
+    // side and settle the limits with the ones of the remote side.
+    // the ring the remote side writes into, sized by local
+    pub(crate) fn recv_buffer(
+        &self,
+        local: &Limits,
+    ) -> Result<(RecvBuffer, UnboundedSender<(u32, u32)>)> {
+        local.validate()?;
+        if local.rqe_count > self.config.cap.max_recv_wr {
+            return Err(Error::InvalidConfig(format!(
+                "rqe_count {} exceeds max_recv_wr {}",
+                local.rqe_count, self.config.cap.max_recv_wr
+            )));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let recv_buffer = RecvBuffer::new(&self.ctx, local.recv_buffer_size as usize, rx)?;
+        Ok((recv_buffer, tx))
     }
 
-    // receive RemoteMR from stream
-    pub async fn recv_mr(&mut self) -> RemoteMR {
-        let mut remote_mr_info = vec![0u8; size_of::<RemoteMR>()];
-        self.tcp_recv(remote_mr_info.as_mut_slice()).await.unwrap();
-        RemoteMR::deserialize(remote_mr_info)
+    pub async fn exchange_recv_buf(
+        &mut self,
+        local: Limits,
+    ) -> Result<(
+        RecvBuffer,
+        RemoteMR,
+        UnboundedSender<(u32, u32)>,
+        Negotiated,
+    )> {
+        let (recv_buffer, tx) = self.recv_buffer(&local)?;
+        // send local_buf to remote
+        let info = BufferInfo {
+            mr: RemoteMR::from_mr(recv_buffer.mr()),
+            limits: local,
+        };
+        write_frame(self, Kind::Buffer, &info).await?;
+        // receive remote_buf from remote
+        let remote: BufferInfo = read_frame(self, Kind::Buffer).await?;
+        remote.validate()?;
+        let negotiated = Negotiated::settle(
+            &local,
+            &remote.limits,
+            self.config.cap.max_send_wr,
+            self.features,
+        );
+        Ok((recv_buffer, remote.mr, tx, negotiated))
     }
 
-    pub fn write_with_imm(&self, local_buf: LocalBuf, remote_buf: RemoteBuf, imm: u32, wr_id: u64) {
+    pub fn write_with_imm(
+        &self,
+        local_buf: LocalBuf,
+        remote_buf: RemoteBuf,
+        imm: u32,
+        wr_id: u64,
+    ) -> Result<()> {
+        // small messages are copied into the WQE, saving the DMA read of the buffer
+        let inline = local_buf.length > 0 && local_buf.length <= self.config.cap.max_inline_data;
         let mut wr_write = WR::new(
             wr_id,
             WRType::SEND,
@@ -250,14 +486,24 @@ impl QP {
                 remote_buf.rkey,
             )),
         );
-        if let Err(e) = wr_write.post_to_qp(self) {
-            error!("wr_write_with_imm error: {:?}", e);
-        }
+        wr_write.set_inline(inline);
+        wr_write.post_to_qp(self)
+    }
+
+    // a zero-length write that only carries imm, it consumes a RQE of the remote side
+    pub fn notify(&self, imm: u32, wr_id: u64) -> Result<()> {
+        let mut wr_write = WR::new(
+            wr_id,
+            WRType::SEND,
+            vec![],
+            Some(RDMA::new(RDMAType::WRITEIMM(imm), 0, 0)),
+        );
+        wr_write.post_to_qp(self)
     }
 
-    pub fn post_null_recv(&self) {
+    pub fn post_null_recv(&self) -> Result<()> {
         let mut wr_recv = WR::new(0, WRType::RECV, vec![], None);
-        wr_recv.post_to_qp(self).unwrap();
+        wr_recv.post_to_qp(self)
     }
 
     // pub fn ask_for_remotemr(&self) -> RemoteMR {
@@ -270,13 +516,14 @@ impl Drop for QP {
         unsafe {
             ibv_destroy_qp(self.inner());
         }
+        self.cq.release(self.config.cap.wrs());
     }
 }
 
 unsafe impl Send for QP {}
 unsafe impl Sync for QP {}
 
-pub fn create_qp(pd: &PD, cq: &CQ, qp_cap: QPCap) -> NonNull<ibv_qp> {
+pub fn create_qp(pd: &PD, cq: &CQ, qp_cap: QPCap) -> Result<NonNull<ibv_qp>> {
     let mut qp_init_attr = unsafe { mem::zeroed::<ibv_qp_init_attr>() };
     qp_init_attr.send_cq = cq.inner();
     qp_init_attr.recv_cq = cq.inner();
@@ -288,9 +535,10 @@ pub fn create_qp(pd: &PD, cq: &CQ, qp_cap: QPCap) -> NonNull<ibv_qp> {
     qp_init_attr.srq = ptr::null_mut();
 
     let qp = unsafe { ibv_create_qp(pd.inner(), &mut qp_init_attr) };
-    NonNull::new(qp).unwrap()
+    NonNull::new(qp).ok_or_else(|| Error::CreateQp(io::Error::last_os_error()))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct QPCap {
     max_send_wr: u32,
     max_recv_wr: u32,
@@ -309,6 +557,26 @@ impl QPCap {
             max_inline_data: 0,
         }
     }
+
+    // the largest message that can be sent inline
+    pub fn max_inline_data(mut self, max_inline_data: u32) -> Self {
+        self.max_inline_data = max_inline_data;
+        self
+    }
+    pub fn max_recv_wr(&self) -> u32 {
+        self.max_recv_wr
+    }
+
+    // a wc for each work request at most, the CQ must hold them all
+    pub fn wrs(&self) -> u32 {
+        self.max_send_wr.saturating_add(self.max_recv_wr)
+    }
+}
+
+impl Default for QPCap {
+    fn default() -> Self {
+        Self::new(MAX_QP_WR, MAX_QP_WR, 5, 5)
+    }
 }
 
 impl Into<ibv_qp_cap> for QPCap {
@@ -323,6 +591,198 @@ impl Into<ibv_qp_cap> for QPCap {
     }
 }
 
+// the attributes of a RC QP and its path to the remote side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QPConfig {
+    pub cap: QPCap,
+    // None for the active mtu of the port
+    pub mtu: Option<Mtu>,
+    // local ack timeout, 4.096us * 2^timeout, 0 for infinite
+    pub timeout: u8,
+    // retries on timeout, at most 7
+    pub retry_cnt: u8,
+    // retries on RNR nack, at most 7 which means infinite
+    pub rnr_retry: u8,
+    // the RNR nack timer code sent to the remote side, at most 31
+    pub min_rnr_timer: u8,
+    // the first PSN sent, None for a random one. the remote side learns it in the handshake
+    pub psn: Option<u32>,
+    // outstanding RDMA read/atomic as initiator and as target
+    pub max_rd_atomic: u8,
+    pub max_dest_rd_atomic: u8,
+    // service level
+    pub sl: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub hop_limit: u8,
+}
+
+impl Default for QPConfig {
+    fn default() -> Self {
+        Self {
+            cap: QPCap::default(),
+            mtu: None,
+            timeout: 14,
+            retry_cnt: 6,
+            rnr_retry: 6,
+            min_rnr_timer: 18,
+            psn: None,
+            max_rd_atomic: 1,
+            max_dest_rd_atomic: 1,
+            sl: 0,
+            traffic_class: 0,
+            flow_label: 0,
+            hop_limit: 255,
+        }
+    }
+}
+
+impl QPConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cap(mut self, cap: QPCap) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: Mtu) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u8) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn retry(mut self, retry_cnt: u8, rnr_retry: u8) -> Self {
+        self.retry_cnt = retry_cnt;
+        self.rnr_retry = rnr_retry;
+        self
+    }
+
+    pub fn min_rnr_timer(mut self, min_rnr_timer: u8) -> Self {
+        self.min_rnr_timer = min_rnr_timer;
+        self
+    }
+
+    pub fn psn(mut self, psn: u32) -> Self {
+        self.psn = Some(psn);
+        self
+    }
+
+    pub fn rd_atomic(mut self, max_rd_atomic: u8, max_dest_rd_atomic: u8) -> Self {
+        self.max_rd_atomic = max_rd_atomic;
+        self.max_dest_rd_atomic = max_dest_rd_atomic;
+        self
+    }
+
+    pub fn sl(mut self, sl: u8) -> Self {
+        self.sl = sl;
+        self
+    }
+
+    pub fn traffic_class(mut self, traffic_class: u8) -> Self {
+        self.traffic_class = traffic_class;
+        self
+    }
+
+    pub fn flow_label(mut self, flow_label: u32) -> Self {
+        self.flow_label = flow_label;
+        self
+    }
+
+    pub fn hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    // check the config against the device and fill in the mtu of the port
+    pub fn validate(&self, device: &Device) -> Result<Self> {
+        self.check(&device.caps(), device.active_mtu(), device.port())
+    }
+
+    // validate against the caps of a device and the active mtu of its port
+    fn check(&self, caps: &DeviceCaps, active_mtu: Mtu, port: u8) -> Result<Self> {
+        let check = |ok: bool, msg: String| {
+            if ok {
+                Ok(())
+            } else {
+                Err(Error::InvalidConfig(msg))
+            }
+        };
+        let cap = &self.cap;
+        check(
+            cap.max_send_wr.max(cap.max_recv_wr) as i64 <= caps.max_qp_wr as i64,
+            format!(
+                "qp wr {}/{} exceeds max_qp_wr {}",
+                cap.max_send_wr, cap.max_recv_wr, caps.max_qp_wr
+            ),
+        )?;
+        check(
+            cap.max_send_sge.max(cap.max_recv_sge) as i64 <= caps.max_sge as i64,
+            format!(
+                "qp sge {}/{} exceeds max_sge {}",
+                cap.max_send_sge, cap.max_recv_sge, caps.max_sge
+            ),
+        )?;
+        let mtu = self.mtu.unwrap_or(active_mtu);
+        check(
+            mtu <= active_mtu,
+            format!(
+                "mtu {} exceeds the active mtu {} of port {}",
+                mtu.bytes(),
+                active_mtu.bytes(),
+                port
+            ),
+        )?;
+        check(
+            self.timeout <= 31,
+            format!("timeout {} exceeds 31", self.timeout),
+        )?;
+        check(
+            self.retry_cnt <= 7,
+            format!("retry_cnt {} exceeds 7", self.retry_cnt),
+        )?;
+        check(
+            self.rnr_retry <= 7,
+            format!("rnr_retry {} exceeds 7", self.rnr_retry),
+        )?;
+        check(
+            self.min_rnr_timer <= 31,
+            format!("min_rnr_timer {} exceeds 31", self.min_rnr_timer),
+        )?;
+        // PSNs are 24 bits
+        let psn = self.psn.unwrap_or(0);
+        check(psn < 1 << 24, format!("psn {} exceeds 24 bits", psn))?;
+        check(
+            self.max_rd_atomic as i32 <= caps.max_qp_init_rd_atom,
+            format!(
+                "max_rd_atomic {} exceeds max_qp_init_rd_atom {}",
+                self.max_rd_atomic, caps.max_qp_init_rd_atom
+            ),
+        )?;
+        check(
+            self.max_dest_rd_atomic as i32 <= caps.max_qp_rd_atom,
+            format!(
+                "max_dest_rd_atomic {} exceeds max_qp_rd_atom {}",
+                self.max_dest_rd_atomic, caps.max_qp_rd_atom
+            ),
+        )?;
+        check(self.sl <= 15, format!("sl {} exceeds 15", self.sl))?;
+        check(
+            self.flow_label < 1 << 20,
+            format!("flow_label {} exceeds 20 bits", self.flow_label),
+        )?;
+        Ok(Self {
+            mtu: Some(mtu),
+            ..self.clone()
+        })
+    }
+}
+
 // pub struct QPInitAttr<'a> {
 //     qp_type: Type,
 //     send_cq: &'a CQ<'a>,
@@ -363,67 +823,93 @@ impl Into<ibv_qp_cap> for QPCap {
 //     }
 // }
 
-pub fn new_ah(enp: EndPoint) -> ibv_ah_attr {
-    let mut ah_attr = unsafe { mem::zeroed::<ibv_ah_attr>() };
-    ah_attr.is_global = 1;
-    // If the destination is in same subnet, the LID of the port to which the subnet delivers the packets to.
-    // If the destination is in another subnet, the LID of the Router
-    ah_attr.dlid = enp.lid;
-    // The local physical port that the packets will be sent from
-    ah_attr.port_num = 1;
-
-    //Gloabel route information about remote end. This is useful when sending packets to another subnet.
-    ah_attr.grh.dgid = ibv_gid { raw: enp.gid };
-    ah_attr.grh.flow_label = 0;
-    ah_attr.grh.hop_limit = 255;
-    ah_attr.grh.traffic_class = 0;
-    ah_attr.grh.sgid_index = 1;
-
-    // service level
-    ah_attr.sl = 0;
-    //The used Source Path Bits. This is useful when LMC is used in the port, i.e. each port covers a range of LIDs.
-    ah_attr.src_path_bits = 0;
-    // A value which limits the rate of packets that being sent to the subnet.
-    ah_attr.static_rate = 0;
-    ah_attr
-}
-
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct EndPoint {
     pub gid: [u8; 16],
     qpn: u32,
     lid: u16,
+    // the first PSN the QP sends
+    psn: u32,
+    // the largest path mtu the QP accepts, in bytes
+    mtu: u32,
 }
 
 impl Debug for EndPoint {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "qpn: {}, lid: {}, gid: {:?}",
-            self.qpn, self.lid, self.gid
+            "qpn: {}, lid: {}, gid: {:?}, psn: {}, mtu: {}",
+            self.qpn, self.lid, self.gid, self.psn, self.mtu
         )
     }
 }
 
 impl EndPoint {
-    pub fn new(qpn: u32, lid: u16, gid: [u8; 16]) -> Self {
-        Self { qpn, lid, gid }
+    pub fn new(qpn: u32, lid: u16, gid: [u8; 16], psn: u32, mtu: Mtu) -> Self {
+        Self {
+            qpn,
+            lid,
+            gid,
+            psn,
+            mtu: mtu.bytes(),
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    pub fn psn(&self) -> u32 {
+        self.psn
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        bincode::deserialize(bytes).unwrap()
+    pub fn validate(&self) -> Result<()> {
+        if self.psn >= 1 << 24 {
+            return Err(Error::Protocol(format!("psn {} exceeds 24 bits", self.psn)));
+        }
+        if Mtu::from_bytes(self.mtu).is_none() {
+            return Err(Error::Protocol(format!("invalid mtu {}", self.mtu)));
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
     }
 }
 
-#[derive(Debug)]
+// every state can be moved to RESET and ERROR
+const ANY_STATE: [Status; 8] = [
+    Status::RESET,
+    Status::INIT,
+    Status::RTR,
+    Status::RTS,
+    Status::SQD,
+    Status::SQE,
+    Status::ERROR,
+    Status::Unknown,
+];
+
+// a random 24 bits PSN
+pub fn fresh_psn() -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0);
+    // spread the low bits, the nanoseconds of two calls can be close
+    nanos.wrapping_mul(0x9E37_79B1) >> 8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
+    RESET,
     INIT,
     RTR,
     RTS,
+    // send queue drained
+    SQD,
+    // send queue error
+    SQE,
     ERROR,
     Unknown,
 }
@@ -431,11 +917,188 @@ pub enum Status {
 impl From<u32> for Status {
     fn from(status: u32) -> Self {
         match status {
+            IBV_QPS_RESET => Status::RESET,
             IBV_QPS_INIT => Status::INIT,
             IBV_QPS_RTR => Status::RTR,
             IBV_QPS_RTS => Status::RTS,
+            IBV_QPS_SQD => Status::SQD,
+            IBV_QPS_SQE => Status::SQE,
             IBV_QPS_ERR => Status::ERROR,
             _ => Status::Unknown,
         }
     }
 }
+
+// the result of QP::query
+pub struct QPAttr {
+    attr: ibv_qp_attr,
+    init_attr: ibv_qp_init_attr,
+}
+
+impl QPAttr {
+    pub fn state(&self) -> Status {
+        Status::from(self.attr.qp_state)
+    }
+
+    pub fn path_mtu(&self) -> Mtu {
+        Mtu::from(self.attr.path_mtu)
+    }
+
+    pub fn dest_qp_num(&self) -> u32 {
+        self.attr.dest_qp_num
+    }
+
+    pub fn sq_psn(&self) -> u32 {
+        self.attr.sq_psn
+    }
+
+    pub fn rq_psn(&self) -> u32 {
+        self.attr.rq_psn
+    }
+
+    pub fn port_num(&self) -> u8 {
+        self.attr.port_num
+    }
+
+    pub fn timeout(&self) -> u8 {
+        self.attr.timeout
+    }
+
+    pub fn retry_cnt(&self) -> u8 {
+        self.attr.retry_cnt
+    }
+
+    pub fn rnr_retry(&self) -> u8 {
+        self.attr.rnr_retry
+    }
+
+    pub fn min_rnr_timer(&self) -> u8 {
+        self.attr.min_rnr_timer
+    }
+
+    pub fn max_rd_atomic(&self) -> u8 {
+        self.attr.max_rd_atomic
+    }
+
+    pub fn max_dest_rd_atomic(&self) -> u8 {
+        self.attr.max_dest_rd_atomic
+    }
+
+    pub fn cap(&self) -> QPCap {
+        let cap = self.attr.cap;
+        QPCap {
+            max_send_wr: cap.max_send_wr,
+            max_recv_wr: cap.max_recv_wr,
+            max_send_sge: cap.max_send_sge,
+            max_recv_sge: cap.max_recv_sge,
+            max_inline_data: cap.max_inline_data,
+        }
+    }
+
+    // the raw attributes for the fields not covered above
+    pub fn raw(&self) -> (&ibv_qp_attr, &ibv_qp_init_attr) {
+        (&self.attr, &self.init_attr)
+    }
+}
+
+impl Debug for QPAttr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QPAttr")
+            .field("state", &self.state())
+            .field("path_mtu", &self.path_mtu())
+            .field("dest_qp_num", &self.dest_qp_num())
+            .field("sq_psn", &self.sq_psn())
+            .field("rq_psn", &self.rq_psn())
+            .field("cap", &self.cap())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::device::AtomicCap;
+
+    fn caps() -> DeviceCaps {
+        DeviceCaps {
+            fw_ver: String::new(),
+            phys_port_cnt: 1,
+            max_qp: 1024,
+            max_qp_wr: 1024,
+            max_sge: 4,
+            max_cq: 1024,
+            max_cqe: 4096,
+            max_mr: 1024,
+            max_mr_size: u64::MAX,
+            max_pd: 1024,
+            max_srq: 0,
+            max_qp_rd_atom: 16,
+            max_qp_init_rd_atom: 16,
+            atomic: AtomicCap::Hca,
+        }
+    }
+
+    fn assert_invalid(config: QPConfig, expect: &str) {
+        match config.check(&caps(), Mtu::Mtu1024, 1) {
+            Err(Error::InvalidConfig(msg)) => assert!(msg.contains(expect), "{}", msg),
+            other => panic!("expect an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        let config = QPConfig::default().check(&caps(), Mtu::Mtu1024, 1).unwrap();
+        // the active mtu fills in None
+        assert_eq!(config.mtu, Some(Mtu::Mtu1024));
+        assert_eq!(
+            QPConfig::default()
+                .mtu(Mtu::Mtu512)
+                .check(&caps(), Mtu::Mtu1024, 1)
+                .unwrap()
+                .mtu,
+            Some(Mtu::Mtu512)
+        );
+    }
+
+    #[test]
+    fn at_the_limits() {
+        let config = QPConfig::default()
+            .cap(QPCap::new(1024, 1024, 4, 4))
+            .mtu(Mtu::Mtu1024)
+            .timeout(31)
+            .retry(7, 7)
+            .min_rnr_timer(31)
+            .psn((1 << 24) - 1)
+            .rd_atomic(16, 16)
+            .sl(15)
+            .flow_label((1 << 20) - 1);
+        assert!(config.check(&caps(), Mtu::Mtu1024, 1).is_ok());
+    }
+
+    #[test]
+    fn beyond_the_caps() {
+        assert_invalid(
+            QPConfig::default().cap(QPCap::new(1025, 1, 1, 1)),
+            "max_qp_wr",
+        );
+        assert_invalid(
+            QPConfig::default().cap(QPCap::new(1, 1025, 1, 1)),
+            "max_qp_wr",
+        );
+        assert_invalid(QPConfig::default().cap(QPCap::new(1, 1, 5, 1)), "max_sge");
+        assert_invalid(QPConfig::default().mtu(Mtu::Mtu2048), "active mtu");
+        assert_invalid(QPConfig::default().rd_atomic(17, 1), "max_qp_init_rd_atom");
+        assert_invalid(QPConfig::default().rd_atomic(1, 17), "max_qp_rd_atom");
+    }
+
+    #[test]
+    fn beyond_the_spec() {
+        assert_invalid(QPConfig::default().timeout(32), "timeout");
+        assert_invalid(QPConfig::default().retry(8, 0), "retry_cnt");
+        assert_invalid(QPConfig::default().retry(0, 8), "rnr_retry");
+        assert_invalid(QPConfig::default().min_rnr_timer(32), "min_rnr_timer");
+        assert_invalid(QPConfig::default().psn(1 << 24), "psn");
+        assert_invalid(QPConfig::default().sl(16), "sl");
+        assert_invalid(QPConfig::default().flow_label(1 << 20), "flow_label");
+    }
+}
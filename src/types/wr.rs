@@ -1,12 +1,13 @@
 //! WR (work request) types.
 
-use super::qp::QP;
+use super::qp::{Status, QP};
+use crate::error::{Error, Result};
 use clippy_utilities::Cast;
 use rdma_sys::{
     ibv_wr_opcode::{IBV_WR_RDMA_READ, IBV_WR_RDMA_WRITE, IBV_WR_RDMA_WRITE_WITH_IMM, IBV_WR_SEND},
     *,
 };
-use std::io::Result;
+use std::io;
 #[derive(Clone)]
 pub struct RDMA {
     r#type: RDMAType,
@@ -34,7 +35,8 @@ pub struct WR {
     wr_type: WRType,
     // todo: unique wr_id
     wr_id: u64,
-    // todo: add send_flags
+    // send the data within the WQE, only for SEND
+    inline: bool,
     // include sg_list and num_sge
     sges: Vec<ibv_sge>,
     rdma: Option<RDMA>,
@@ -46,13 +48,27 @@ impl WR {
         Self {
             wr_type,
             wr_id,
+            inline: false,
             sges,
             rdma,
         }
     }
 
+    pub fn set_inline(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
     // build WR, and post it to QP.
     pub fn post_to_qp(&mut self, qp: &QP) -> Result<()> {
+        match self.wr_type {
+            WRType::SEND => qp.expect(&[Status::RTS], "post send")?,
+            WRType::RECV => qp.expect(&[Status::INIT, Status::RTR, Status::RTS], "post recv")?,
+        }
+        self.post(qp)
+    }
+
+    // post without checking the state of the QP, WRs posted in ERROR are flushed
+    pub(crate) fn post(&mut self, qp: &QP) -> Result<()> {
         match self.wr_type {
             WRType::SEND => {
                 let mut wr = unsafe { std::mem::zeroed::<ibv_send_wr>() };
@@ -87,12 +103,15 @@ impl WR {
                     }
                 }
                 // send operation will be signaled
-                wr.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0.cast();
+                let mut flags = ibv_send_flags::IBV_SEND_SIGNALED;
+                if self.inline {
+                    flags = flags | ibv_send_flags::IBV_SEND_INLINE;
+                }
+                wr.send_flags = flags.0.cast();
                 let mut bad_send_wr = std::ptr::null_mut();
                 let ret = unsafe { ibv_post_send(qp.inner(), &mut wr, &mut bad_send_wr) };
                 if ret != 0 {
-                    println!("ret: {}, qp_status: {:?}", ret, qp.status());
-                    return Err(std::io::Error::last_os_error());
+                    return Err(Error::PostWr(io::Error::last_os_error()));
                 }
             }
             WRType::RECV => {
@@ -105,9 +124,8 @@ impl WR {
                 let mut bad_recv_wr = std::ptr::null_mut();
                 let ret = unsafe { ibv_post_recv(qp.inner(), &mut wr, &mut bad_recv_wr) };
                 if ret != 0 {
-                    println!("ret: {}, qp_status: {:?}", ret, qp.status());
+                    return Err(Error::PostWr(io::Error::last_os_error()));
                 }
-                return Ok(());
             }
         }
         Ok(())